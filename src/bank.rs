@@ -1,17 +1,48 @@
+use std::cmp;
 use std::mem;
 
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use log::{debug, trace};
 
+use crate::error::{Error, Result};
+
+/// Farthest back a match can refer, the widest offset field has 12 bits
+const MAX_OFFSET: usize = 0xfff;
+const MAX_MATCH_LEN: usize = 0x100;
+const MAX_LITERAL_LEN: usize = 0x108;
+/// Farthest back the 2, 3 and 4 byte matches with short offsets can refer
+const SHORT_MAX_OFFSETS: [usize; 3] = [0xff, 0x1ff, 0x3ff];
+/// Number of earlier positions that are tried for each match
+const MAX_CHAIN: usize = 1024;
+
 pub enum Bank {
     Uncompressed(Vec<u8>),
     Compressed(Vec<u8>),
 }
 
 impl Bank {
-    pub fn data(self) -> Vec<u8> {
+    /// Compresses `data`, or keeps it as is when packing does not make it
+    /// smaller. A resource is only unpacked when its packed size differs
+    /// from its size.
+    pub fn pack(data: Vec<u8>) -> Bank {
+        let packed = Packer::new(&data).pack();
+        if packed.len() < data.len() {
+            Bank::Compressed(packed)
+        } else {
+            Bank::Uncompressed(data)
+        }
+    }
+
+    /// The bytes as stored in the bank file
+    pub fn stored_data(&self) -> &[u8] {
         match self {
-            Bank::Uncompressed(data) => data,
+            Bank::Uncompressed(data) | Bank::Compressed(data) => data,
+        }
+    }
+
+    pub fn data(self) -> Result<Vec<u8>> {
+        match self {
+            Bank::Uncompressed(data) => Ok(data),
             Bank::Compressed(data) => {
                 let mut unpacker = Unpacker::new(&data);
                 unpacker.unpack()
@@ -27,11 +58,13 @@ struct Unpacker<'a> {
     datasize: u32,
     crc: u32,
     chk: u32,
+    /// Set once the first word of the data has been read
+    exhausted: bool,
     output: Vec<u8>,
 }
 
 impl<'a> Unpacker<'a> {
-    fn new(data: &'a [u8]) -> Unpacker {
+    fn new(data: &'a [u8]) -> Unpacker<'a> {
         Unpacker {
             data,
             i: 0,
@@ -39,63 +72,83 @@ impl<'a> Unpacker<'a> {
             datasize: 0,
             crc: 0,
             chk: 0,
+            exhausted: false,
             output: Vec::new(),
         }
     }
 
-    fn read_reverse_be_u32(&mut self) -> u32 {
+    fn read_reverse_be_u32(&mut self) -> Result<u32> {
+        if self.exhausted {
+            return Err(Error::BadPackedData);
+        }
         let result = BigEndian::read_u32(&self.data[self.i..]);
         if self.i >= 4 {
             self.i -= 4;
+        } else {
+            self.exhausted = true;
         }
-        result
+        Ok(result)
     }
 
-    fn next_chunk(&mut self) -> bool {
+    fn next_chunk(&mut self) -> Result<bool> {
         let mut cf = self.rcr(false);
         if self.chk == 0 {
             trace!("i = {}", self.i);
-            self.chk = self.read_reverse_be_u32();
+            self.chk = self.read_reverse_be_u32()?;
             self.crc ^= self.chk;
             cf = self.rcr(true);
         }
-        cf
+        Ok(cf)
     }
 
-    fn dec_unk1(&mut self, num_chunks: u32, add_count: u32) {
-        let mut count = self.get_code(num_chunks) + add_count + 1;
+    /// Takes `count` bytes off the size left to unpack
+    fn consume(&mut self, count: u32) -> Result<()> {
+        self.datasize = self
+            .datasize
+            .checked_sub(count)
+            .ok_or(Error::BadPackedData)?;
+        Ok(())
+    }
+
+    fn dec_unk1(&mut self, num_chunks: u32, add_count: u32) -> Result<()> {
+        let mut count = self.get_code(num_chunks)? + add_count + 1;
         trace!("dec_unk1({}, {}) count={}", num_chunks, add_count, count);
-        self.datasize -= count;
+        self.consume(count)?;
         while count > 0 {
             count -= 1;
-            let val = self.get_code(8) as u8;
+            let val = self.get_code(8)? as u8;
             self.output.push(val);
         }
+        Ok(())
     }
 
-    fn dec_unk2(&mut self, num_chunks: u32) {
-        let i = self.get_code(num_chunks) as usize;
+    fn dec_unk2(&mut self, num_chunks: u32) -> Result<()> {
+        let i = self.get_code(num_chunks)? as usize;
         let mut count = self.size + 1;
         trace!("dec_unk2({}) i={} count={}", num_chunks, i, count);
-        self.datasize -= count;
+        if i == 0 || i > self.output.len() {
+            return Err(Error::BadPackedData);
+        }
+        self.consume(count)?;
         while count > 0 {
             count -= 1;
             let val = self.output[self.output.len() - i];
             self.output.push(val);
         }
+        Ok(())
     }
 
-    fn get_code(&mut self, num_chunks: u32) -> u32 {
+    fn get_code(&mut self, num_chunks: u32) -> Result<u32> {
         let mut num_chunks = num_chunks;
         let mut c = 0;
         while num_chunks > 0 {
             num_chunks -= 1;
             c <<= 1;
-            if self.next_chunk() {
+            if self.next_chunk()? {
                 c |= 1;
             }
         }
-        c
+        Ok(c)
     }
 
     fn rcr(&mut self, cf: bool) -> bool {
@@ -107,41 +160,359 @@ impl<'a> Unpacker<'a> {
         rcf
     }
 
-    fn unpack(&mut self) -> Vec<u8> {
+    fn unpack(&mut self) -> Result<Vec<u8>> {
         debug!("Unpack()");
+        if self.data.len() < 12 {
+            return Err(Error::BadPackedData);
+        }
         self.i = self.data.len() - 4;
         self.size = 0;
-        self.datasize = self.read_reverse_be_u32();
-        self.crc = self.read_reverse_be_u32();
-        self.chk = self.read_reverse_be_u32();
+        self.datasize = self.read_reverse_be_u32()?;
+        self.crc = self.read_reverse_be_u32()?;
+        self.chk = self.read_reverse_be_u32()?;
         self.crc ^= self.chk;
         while self.datasize > 0 {
-            if !self.next_chunk() {
+            if !self.next_chunk()? {
                 self.size = 1;
-                if !self.next_chunk() {
-                    self.dec_unk1(3, 0);
+                if !self.next_chunk()? {
+                    self.dec_unk1(3, 0)?;
                 } else {
-                    self.dec_unk2(8);
+                    self.dec_unk2(8)?;
                 }
             } else {
-                let c = self.get_code(2);
+                let c = self.get_code(2)?;
                 if c == 3 {
-                    self.dec_unk1(8, 8);
+                    self.dec_unk1(8, 8)?;
                 } else if c < 2 {
                     self.size = c + 2;
-                    self.dec_unk2(c + 9);
+                    self.dec_unk2(c + 9)?;
                 } else {
-                    self.size = self.get_code(8);
-                    self.dec_unk2(12);
+                    self.size = self.get_code(8)?;
+                    self.dec_unk2(12)?;
                 }
             }
         }
         if self.crc != 0 {
-            panic!("CRC Error: {}", self.crc);
+            return Err(Error::Crc(self.crc));
         }
         self.output.reverse();
         let mut new_output = Vec::new();
         mem::swap(&mut self.output, &mut new_output);
-        new_output
+        Ok(new_output)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Token {
+    Literals(usize),
+    Match { len: usize, offset: usize },
+}
+
+impl Token {
+    fn len(self) -> usize {
+        match self {
+            Token::Literals(len) | Token::Match { len, .. } => len,
+        }
+    }
+
+    /// Number of bits the token takes in the stream
+    fn cost(self) -> usize {
+        match self {
+            Token::Literals(len) if len <= 8 => 5 + len * 8,
+            Token::Literals(len) => 11 + len * 8,
+            Token::Match { len: 2, offset } if offset <= SHORT_MAX_OFFSETS[0] => 10,
+            Token::Match { len: 3, offset } if offset <= SHORT_MAX_OFFSETS[1] => 12,
+            Token::Match { len: 4, offset } if offset <= SHORT_MAX_OFFSETS[2] => 13,
+            Token::Match { .. } => 23,
+        }
+    }
+}
+
+/// Nearest matches at a position, by the length they reach
+#[derive(Default)]
+struct Matches {
+    /// Offsets of the nearest matches of at least 2, 3 and 4 bytes
+    short: [Option<usize>; 3],
+    /// Longest match within reach, as length and offset
+    long: Option<(usize, usize)>,
+}
+
+/// Compresses data to the stream that `Unpacker` reads. The data is packed
+/// from its end, as that is where unpacking starts, and the bits are
+/// stored in words read backwards from the end of the stream. The last
+/// words hold the first bits, a checksum of all bit words and the
+/// unpacked size.
+pub struct Packer<'a> {
+    data: &'a [u8],
+    bits: Vec<bool>,
+}
+
+impl<'a> Packer<'a> {
+    pub fn new(data: &'a [u8]) -> Packer<'a> {
+        Packer {
+            data,
+            bits: Vec::new(),
+        }
+    }
+
+    pub fn pack(&mut self) -> Vec<u8> {
+        debug!("Pack()");
+        let reversed: Vec<u8> = self.data.iter().rev().cloned().collect();
+        let mut pos = 0;
+        for token in Packer::parse(&reversed) {
+            self.put_token(token, &reversed[pos..pos + token.len()]);
+            pos += token.len();
+        }
+        self.finish()
+    }
+
+    /// Finds the cheapest sequence of tokens, working back from the end of
+    /// the data
+    fn parse(data: &[u8]) -> Vec<Token> {
+        let matches = Packer::find_matches(data);
+        let mut cost = vec![0; data.len() + 1];
+        let mut choice = vec![Token::Literals(0); data.len()];
+        for pos in (0..data.len()).rev() {
+            let mut candidates = Vec::new();
+            for len in 1..=cmp::min(MAX_LITERAL_LEN, data.len() - pos) {
+                candidates.push(Token::Literals(len));
+            }
+            let m = &matches[pos];
+            for (i, offset) in m.short.iter().enumerate() {
+                if let Some(offset) = *offset {
+                    candidates.push(Token::Match { len: i + 2, offset });
+                }
+            }
+            if let Some((max_len, offset)) = m.long {
+                for len in 2..=max_len {
+                    candidates.push(Token::Match { len, offset });
+                }
+            }
+
+            let (best, best_cost) = candidates
+                .into_iter()
+                .map(|token| (token, token.cost() + cost[pos + token.len()]))
+                .min_by_key(|(_, cost)| *cost)
+                .expect("Expected a literal token");
+            cost[pos] = best_cost;
+            choice[pos] = best;
+        }
+
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            tokens.push(choice[pos]);
+            pos += choice[pos].len();
+        }
+        trace!("Packed {} bytes to {} bits", data.len(), cost[0]);
+        tokens
+    }
+
+    /// Looks for earlier occurrences of the data at every position, through
+    /// chains of positions that start with the same two bytes
+    fn find_matches(data: &[u8]) -> Vec<Matches> {
+        let mut matches = Vec::with_capacity(data.len());
+        let mut head = vec![None; 0x10000];
+        let mut prev = vec![None; data.len()];
+        for pos in 0..data.len() {
+            let mut m = Matches::default();
+            if pos + 1 >= data.len() {
+                matches.push(m);
+                continue;
+            }
+            let key = BigEndian::read_u16(&data[pos..]) as usize;
+            let max_len = cmp::min(MAX_MATCH_LEN, data.len() - pos);
+            let mut candidate = head[key];
+            let mut chain = 0;
+            while let Some(start) = candidate {
+                let offset = pos - start;
+                if offset > MAX_OFFSET || chain == MAX_CHAIN {
+                    break;
+                }
+                let len = (0..max_len)
+                    .take_while(|i| data[start + i] == data[pos + i])
+                    .count();
+                for (i, short) in m.short.iter_mut().enumerate() {
+                    if short.is_none() && len >= i + 2 && offset <= SHORT_MAX_OFFSETS[i] {
+                        *short = Some(offset);
+                    }
+                }
+                match m.long {
+                    Some((long, _)) if long >= len => {}
+                    _ => m.long = Some((len, offset)),
+                }
+                if len == max_len {
+                    break;
+                }
+                candidate = prev[start];
+                chain += 1;
+            }
+            prev[pos] = head[key];
+            head[key] = Some(pos);
+            matches.push(m);
+        }
+        matches
+    }
+
+    fn put_bits(&mut self, value: usize, count: u32) {
+        for i in (0..count).rev() {
+            self.bits.push((value >> i) & 1 != 0);
+        }
+    }
+
+    fn put_token(&mut self, token: Token, data: &[u8]) {
+        match token {
+            Token::Literals(len) if len <= 8 => {
+                self.put_bits(0b00, 2);
+                self.put_bits(len - 1, 3);
+            }
+            Token::Literals(len) => {
+                self.put_bits(0b111, 3);
+                self.put_bits(len - 9, 8);
+            }
+            Token::Match { len: 2, offset } if offset <= SHORT_MAX_OFFSETS[0] => {
+                self.put_bits(0b01, 2);
+                self.put_bits(offset, 8);
+            }
+            Token::Match { len: 3, offset } if offset <= SHORT_MAX_OFFSETS[1] => {
+                self.put_bits(0b100, 3);
+                self.put_bits(offset, 9);
+            }
+            Token::Match { len: 4, offset } if offset <= SHORT_MAX_OFFSETS[2] => {
+                self.put_bits(0b101, 3);
+                self.put_bits(offset, 10);
+            }
+            Token::Match { len, offset } => {
+                self.put_bits(0b110, 3);
+                self.put_bits(len - 1, 8);
+                self.put_bits(offset, 12);
+            }
+        }
+        if let Token::Literals(_) = token {
+            for b in data {
+                self.put_bits(*b as usize, 8);
+            }
+        }
+    }
+
+    /// Stores the bits in words, 32 to a word and lowest bit first. The
+    /// first word read holds the bits left over, under a marker bit.
+    fn finish(&mut self) -> Vec<u8> {
+        let first_len = self.bits.len() % 32;
+        let mut chunks = vec![&self.bits[..first_len]];
+        chunks.extend(self.bits[first_len..].chunks(32));
+        let words: Vec<u32> = chunks
+            .iter()
+            .enumerate()
+            .map(|(n, chunk)| {
+                let mut word = if n == 0 { 1 << first_len } else { 0 };
+                for (i, bit) in chunk.iter().enumerate() {
+                    if *bit {
+                        word |= 1 << i;
+                    }
+                }
+                word
+            })
+            .collect();
+
+        let mut output = Vec::with_capacity(words.len() * 4 + 8);
+        let mut crc = 0;
+        for word in words.iter().rev() {
+            crc ^= word;
+            output.write_u32::<BigEndian>(*word).unwrap();
+        }
+        output.write_u32::<BigEndian>(crc).unwrap();
+        output
+            .write_u32::<BigEndian>(self.data.len() as u32)
+            .unwrap();
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes from a linear congruential generator, which hardly repeat
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn words(packed: &[u8]) -> Vec<u32> {
+        packed.chunks(4).map(BigEndian::read_u32).collect()
+    }
+
+    fn check_round_trip(data: &[u8]) -> Vec<u8> {
+        let packed = Packer::new(data).pack();
+        assert_eq!(packed.len() % 4, 0);
+        let words = words(&packed);
+        let (bit_words, trailer) = words.split_at(words.len() - 2);
+        assert_eq!(trailer[0], bit_words.iter().fold(0, |crc, word| crc ^ word));
+        assert_eq!(trailer[1], data.len() as u32);
+        assert_eq!(Unpacker::new(&packed).unpack().unwrap(), data);
+        packed
+    }
+
+    #[test]
+    fn empty_data_round_trips() {
+        assert_eq!(check_round_trip(&[]).len(), 12);
+    }
+
+    #[test]
+    fn short_data_round_trips() {
+        check_round_trip(&[0x42]);
+        check_round_trip(&[0x42, 0x42]);
+        check_round_trip(&[1, 2, 3]);
+    }
+
+    #[test]
+    fn data_without_repeats_round_trips() {
+        check_round_trip(&noise(5000));
+    }
+
+    #[test]
+    fn long_runs_round_trip_and_shrink() {
+        let mut data = vec![0xaa; 30_000];
+        data.extend(noise(300));
+        data.extend(vec![0; 0x1234]);
+        let packed = check_round_trip(&data);
+        assert!(packed.len() < data.len() / 20);
+    }
+
+    #[test]
+    fn bank_keeps_data_that_does_not_shrink() {
+        let data = noise(100);
+        match Bank::pack(data.clone()) {
+            Bank::Uncompressed(stored) => assert_eq!(stored, data),
+            Bank::Compressed(_) => panic!("Expected uncompressed data"),
+        }
+        let data = vec![7; 1000];
+        match Bank::pack(data.clone()) {
+            Bank::Compressed(stored) => assert_eq!(Bank::Compressed(stored).data().unwrap(), data),
+            Bank::Uncompressed(_) => panic!("Expected compressed data"),
+        }
+    }
+
+    #[test]
+    fn corrupt_data_is_rejected() {
+        let data: Vec<u8> = b"Another World "
+            .iter()
+            .cycle()
+            .take(2000)
+            .cloned()
+            .collect();
+        let packed = Packer::new(&data).pack();
+        assert!(Unpacker::new(&packed[..8]).unpack().is_err());
+        for i in 0..packed.len() - 8 {
+            let mut corrupt = packed.clone();
+            corrupt[i] ^= 0x10;
+            assert!(Unpacker::new(&corrupt).unpack().is_err(), "byte {}", i);
+        }
     }
 }
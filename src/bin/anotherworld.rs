@@ -1,12 +1,25 @@
 use std::path::PathBuf;
+use std::process;
 
 use pretty_env_logger;
 use structopt::StructOpt;
 
+use anotherworld::bindings::KeyBindings;
+use anotherworld::clock::{Clock, SystemClock, VirtualClock};
+use anotherworld::config::Config;
 use anotherworld::engine;
+use anotherworld::error::Result;
+use anotherworld::headless;
+use anotherworld::image::ImageFormat;
+use anotherworld::mixer::Resampling;
+use anotherworld::movie::{Movie, MovieHeader, MoviePlayer, MovieRecorder};
+use anotherworld::record::RecordFormat;
 use anotherworld::resource;
 use anotherworld::resource::AssetPlatform;
+use anotherworld::screenshot::ScreenshotOptions;
+use anotherworld::sfxplayer::SongEnd;
 use anotherworld::sys;
+use anotherworld::sys::{Backend, Scaling, WindowOptions, WindowSize};
 use anotherworld::video;
 use anotherworld::vm;
 
@@ -28,16 +41,140 @@ struct Opt {
     /// Enable hires graphics
     #[structopt(long)]
     hires: bool,
+    /// Run without a window, writing every presented frame to this directory
+    #[structopt(parse(from_os_str), long, name = "DIR")]
+    headless: Option<PathBuf>,
+    /// Image format of headless frames (png or ppm)
+    #[structopt(long, default_value = "png")]
+    frame_format: ImageFormat,
+    /// Quit after this many frames have been written in headless mode
+    #[structopt(long)]
+    frame_limit: Option<u64>,
+    /// Game speed in percent, 0 runs as fast as possible
+    #[structopt(long, default_value = "100")]
+    speed: u32,
+    /// Run on virtual time so every run takes the same number of frames
+    #[structopt(long)]
+    virtual_clock: bool,
+    /// Seconds of play that can be rewound, 0 disables rewinding
+    #[structopt(long, default_value = "10")]
+    rewind_seconds: u64,
+    /// Seed for the random number generator of the game
+    #[structopt(long)]
+    seed: Option<i16>,
+    /// Record the player input to a movie file
+    #[structopt(parse(from_os_str), long)]
+    record_movie: Option<PathBuf>,
+    /// Play back the player input from a movie file
+    #[structopt(parse(from_os_str), long, conflicts_with = "record-movie")]
+    play_movie: Option<PathBuf>,
+    /// Analog stick deadzone in percent
+    #[structopt(long)]
+    deadzone: Option<u8>,
+    /// Key bindings file, defaults to bindings.toml in the user config directory
+    #[structopt(parse(from_os_str), long)]
+    bindings: Option<PathBuf>,
+    /// Print the active key bindings and exit
+    #[structopt(long)]
+    print_bindings: bool,
+    /// Start in fullscreen mode
+    #[structopt(long)]
+    fullscreen: bool,
+    /// Initial window size
+    #[structopt(long, default_value = "1280x800")]
+    window_size: WindowSize,
+    /// Scaling mode: fit, integer or aspect (4:3 correction)
+    #[structopt(long, default_value = "fit")]
+    scaling: Scaling,
+    /// Directory for screenshots, defaults to anotherworld in the pictures directory
+    #[structopt(parse(from_os_str), long)]
+    screenshot_dir: Option<PathBuf>,
+    /// Save all four pages and the palette along with each screenshot
+    #[structopt(long)]
+    screenshot_bundle: bool,
+    /// Capture the presented frames and audio to a directory. Audio is only
+    /// written to the capture, the sound device stays silent while recording
+    #[structopt(parse(from_os_str), long)]
+    record: Option<PathBuf>,
+    /// Format of recorded frames: y4m, png or ppm
+    #[structopt(long, default_value = "y4m")]
+    record_format: RecordFormat,
+    /// Seconds of frames kept for the instant replay GIF, 0 disables it
+    #[structopt(long, default_value = "10")]
+    replay_seconds: u64,
+    /// Stereo separation in percent, 0 is mono and 100 is hard Amiga panning
+    #[structopt(long, default_value = "100")]
+    stereo_separation: u8,
+    /// Audio output rate, such as 44100 or 48000. Defaults to the rate of
+    /// the audio device.
+    #[structopt(long)]
+    sample_rate: Option<u32>,
+    /// Sample resampling: nearest, linear or blep (band-limited like Paula)
+    #[structopt(long, default_value = "linear")]
+    resampling: Resampling,
+    /// Loop music back to this order at the end of a song instead of stopping
+    #[structopt(long)]
+    music_loop: Option<u8>,
+    /// Exit when the music reaches the end of its last order, for capturing
+    /// a track with --record
+    #[structopt(long)]
+    exit_on_music_end: bool,
+    /// Config file, defaults to config.toml in the user config directory
+    #[structopt(parse(from_os_str), long)]
+    config: Option<PathBuf>,
+    /// Master volume in percent
+    #[structopt(long)]
+    master_volume: Option<u8>,
+    /// Music volume in percent
+    #[structopt(long)]
+    music_volume: Option<u8>,
+    /// Sound effects volume in percent
+    #[structopt(long)]
+    effects_volume: Option<u8>,
+    /// Start with the sound muted
+    #[structopt(long)]
+    mute: bool,
 }
 
-fn main() -> std::io::Result<()> {
+fn main() {
     let opt = Opt::from_args();
     pretty_env_logger::init();
+    if let Err(e) = run(opt) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let bindings = match opt.bindings.clone().or_else(KeyBindings::default_path) {
+        Some(path) => KeyBindings::load(&path)?,
+        None => KeyBindings::new(),
+    };
+    if opt.print_bindings {
+        print!("{}", bindings.to_toml());
+        return Ok(());
+    }
+    let config = match opt.config.clone().or_else(Config::default_path) {
+        Some(path) => Config::load(&path)?,
+        None => Config::new(),
+    };
+
     let memlist_reader = resource::MemlistReader::detect_platform(opt.asset_path);
     let resource = memlist_reader.read_memlist()?;
     let asset_platform = resource.asset_platform;
 
-    let sdl_context = sdl2::init().unwrap();
+    let movie_player = match &opt.play_movie {
+        Some(path) => Some(MoviePlayer::open(path)?),
+        None => None,
+    };
+    let (game_part, seed, bypass) = match &movie_player {
+        Some(player) => (
+            player.header.game_part,
+            Some(player.header.seed),
+            player.header.bypass,
+        ),
+        None => (opt.game_part, opt.seed, !opt.no_bypass),
+    };
 
     let (width, height, zoom) = if opt.hires {
         (640, 400, 2)
@@ -45,10 +182,81 @@ fn main() -> std::io::Result<()> {
         (320, 200, 1)
     };
 
-    let sys = sys::SDLSys::new(sdl_context, width, height);
+    let clock: Box<dyn Clock> = if opt.headless.is_some() {
+        Box::new(VirtualClock::new(0))
+    } else if opt.virtual_clock {
+        Box::new(VirtualClock::new(opt.speed))
+    } else {
+        Box::new(SystemClock::new(opt.speed))
+    };
+    let headless = opt.headless.is_some();
+    let sys: Box<dyn Backend> = if let Some(output_dir) = opt.headless {
+        Box::new(headless::HeadlessSys::new(
+            output_dir,
+            opt.frame_format,
+            opt.frame_limit,
+            width,
+            height,
+        )?)
+    } else {
+        let sdl_context = sdl2::init().unwrap();
+        let window_options = WindowOptions {
+            size: opt.window_size,
+            fullscreen: opt.fullscreen,
+            scaling: opt.scaling,
+        };
+        let mut sdl_sys =
+            sys::SDLSys::with_window_options(sdl_context, width, height, &window_options);
+        if let Some(percent) = opt.deadzone {
+            sdl_sys.set_deadzone(percent);
+        }
+        sdl_sys.set_sample_rate(opt.sample_rate);
+        sdl_sys.set_bindings(bindings);
+        Box::new(sdl_sys)
+    };
     let video = video::Video::new(width, height);
     let mut vm = vm::VirtualMachine::new(resource, video, sys, zoom);
-    if !opt.no_bypass {
+    vm.set_clock(clock);
+    vm.set_stereo_separation(opt.stereo_separation);
+    vm.set_resampling(opt.resampling);
+    let mut volumes = config.volumes;
+    if let Some(level) = opt.master_volume {
+        volumes.master = level.min(100);
+    }
+    if let Some(level) = opt.music_volume {
+        volumes.music = level.min(100);
+    }
+    if let Some(level) = opt.effects_volume {
+        volumes.effects = level.min(100);
+    }
+    volumes.muted |= opt.mute;
+    vm.set_volumes(volumes);
+    if let Some(order) = opt.music_loop {
+        vm.set_song_end(SongEnd::Loop(order));
+    }
+    // With a window the audio device decides the rate
+    if let (true, Some(sample_rate)) = (headless, opt.sample_rate) {
+        vm.set_sample_rate(sample_rate);
+    }
+    if opt.rewind_seconds > 0 {
+        vm.enable_rewind(opt.rewind_seconds * 1000);
+    }
+    if opt.replay_seconds > 0 {
+        vm.enable_replay(opt.replay_seconds * 1000);
+    }
+    let mut screenshot_options = ScreenshotOptions::new();
+    if let Some(dir) = opt.screenshot_dir {
+        screenshot_options.dir = dir;
+    }
+    screenshot_options.bundle = opt.screenshot_bundle;
+    vm.set_screenshot_options(screenshot_options);
+    if let Some(output_dir) = opt.record {
+        vm.start_recording(output_dir, opt.record_format)?;
+    }
+    if let Some(seed) = seed {
+        vm.set_random_seed(seed);
+    }
+    if bypass {
         vm.set_variable(0xbc, 0x10);
         vm.set_variable(0xc6, 0x80);
         vm.set_variable(0xdc, 33);
@@ -59,8 +267,19 @@ fn main() -> std::io::Result<()> {
         vm.set_variable(0xf2, value);
     }
 
-    let mut engine = engine::Engine::new(vm, opt.game_part);
+    if let Some(player) = movie_player {
+        vm.set_movie(Movie::Playing(player));
+    } else if let Some(path) = opt.record_movie {
+        let header = MovieHeader {
+            game_part,
+            seed: vm.random_seed(),
+            bypass,
+        };
+        vm.set_movie(Movie::Recording(MovieRecorder::create(&path, header)?));
+    }
+
+    let mut engine = engine::Engine::new(vm, game_part)?;
+    engine.set_exit_on_music_end(opt.exit_on_music_end);
 
-    engine.run();
-    Ok(())
+    engine.run()
 }
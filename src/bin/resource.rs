@@ -1,13 +1,17 @@
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::{thread, time};
 
 use pretty_env_logger;
 use structopt::StructOpt;
 
+use anotherworld::error::Result;
+use anotherworld::manifest;
 use anotherworld::mixer;
+use anotherworld::release;
 use anotherworld::resource;
 use anotherworld::sys;
+use anotherworld::sys::Backend;
 use anotherworld::video;
 
 #[derive(Debug, StructOpt)]
@@ -26,14 +30,80 @@ struct Opt {
 #[derive(Debug, StructOpt)]
 enum Command {
     List { },
+    /// Unpack every resource to its own file, along with a manifest
+    Extract {
+        #[structopt(parse(from_os_str))]
+        output_dir: PathBuf,
+    },
+    /// Unpack every resource and report the ones that are damaged
+    Verify { },
+    /// Build Memlist.bin and the bank files from an extracted directory
+    Pack {
+        #[structopt(parse(from_os_str))]
+        input_dir: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output_dir: PathBuf,
+    },
 }
 
-fn main() -> std::io::Result<()> {
+fn main() {
     let opt = Opt::from_args();
     pretty_env_logger::init();
-    let memlist_reader = resource::MemlistReader::detect_platform(opt.asset_path);
-    let mut res = memlist_reader.read_memlist()?;
+    if let Err(e) = run(opt) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let asset_path = opt.asset_path;
+    let read_memlist = || resource::MemlistReader::detect_platform(asset_path.clone()).read_memlist();
+
+    match opt.cmd {
+        Command::List { } => list(read_memlist()?),
+        Command::Extract { output_dir } => {
+            let res = read_memlist()?;
+            let manifest = manifest::extract(&res, &output_dir)?;
+            for entry in manifest.entries {
+                if let Some(file) = entry.file {
+                    println!(
+                        "{:03} {:>14} {:6} -> {}",
+                        entry.index, entry.entry_type, entry.size, file
+                    );
+                }
+            }
+            Ok(())
+        },
+        Command::Verify { } => verify(&asset_path),
+        Command::Pack { input_dir, output_dir } => manifest::pack(&input_dir, &output_dir),
+    }
+}
 
+fn verify(asset_path: &Path) -> Result<()> {
+    let res = resource::MemlistReader::detect_platform(asset_path.to_path_buf()).read_memlist()?;
+    let fingerprint = release::Fingerprint::compute(asset_path, res.asset_platform)?;
+    match fingerprint.identify(res.asset_platform) {
+        Some(release) => println!("Release: {}", release.name),
+        None => println!("Unknown release"),
+    }
+    for (name, hash) in fingerprint.files.iter() {
+        println!("{:12} {}", name, hash);
+    }
+
+    let problems = res.verify();
+    for (resource_id, e) in problems.iter() {
+        let entry_type = res.mem_list[*resource_id as usize].entry_type;
+        println!("{:03} {:?}: {}", resource_id, entry_type, e);
+    }
+    let total = res.mem_list.len();
+    println!("{} of {} resources OK", total - problems.len(), total);
+    if !problems.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn list(mut res: resource::Resource) -> Result<()> {
     let sdl_context = sdl2::init().unwrap();
 
     let (width, height, _zoom) = if false {
@@ -69,33 +139,28 @@ fn main() -> std::io::Result<()> {
 
     let mut sys = sys::SDLSys::new(sdl_context, width, height);
 
-    let mixer = Arc::new(RwLock::new(mixer::Mixer::new()));
-    sys.start_audio(mixer.clone());
+    let (mut mixer, audio) = mixer::MixerHandle::new();
+    sys.start_audio(audio);
 
-    match opt.cmd {
-        Command::List { } => {
-            for i in 0..res.mem_list.len() {
-                println!("i : {}", i);
-                if res.mem_list[i].entry_type == resource::EntryType::Sound {
-                    let resource_id = i as u16;
-                    video.fill_video_page(0, 0);
-                    video.draw_string(1, 1, 10, &format!("Resource: {:03} - {:#?}", i, res.mem_list[i].entry_type), 1);
-                    video.update_display(&mut sys, 0);
-
-                    res.load_memory_entry(resource_id);
-                    if let Some(chunk) = res.get_entry_mixer_chunk(resource_id) {
-                        let mut write_guard = mixer.write().expect("Expected non-poisoned RwLock");
-                        let vol = 255;
-                        write_guard.play_channel(0, chunk, 10000, vol);
-                    }
-                    if sys.process_events().quit == true {
-                        return Ok(());
-                    }
-                    res.invalidate_resource();
-                    thread::sleep(time::Duration::from_millis(1000));
-                }
+    for i in 0..res.mem_list.len() {
+        println!("i : {}", i);
+        if res.mem_list[i].entry_type == resource::EntryType::Sound {
+            let resource_id = i as u16;
+            video.fill_video_page(0, 0);
+            video.draw_string(1, 1, 10, &format!("Resource: {:03} - {:#?}", i, res.mem_list[i].entry_type), 1);
+            video.update_display(&mut sys, 0);
+
+            res.load_memory_entry(resource_id)?;
+            if let Some(chunk) = res.get_entry_mixer_chunk(resource_id) {
+                let vol = 255;
+                mixer.play_channel(0, chunk, 10000, vol, mixer::SoundKind::Effect);
             }
-        },
+            if sys.process_events().quit == true {
+                return Ok(());
+            }
+            res.invalidate_resource();
+            thread::sleep(time::Duration::from_millis(1000));
+        }
     }
     Ok(())
 }
@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use sdl2::keyboard::Keycode;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Left,
+    Right,
+    Up,
+    Down,
+    Button,
+    Code,
+    Pause,
+    Quit,
+    Save,
+    Load,
+    Slot(i8),
+    Turbo,
+    Rewind,
+    Fullscreen,
+    Screenshot,
+    Replay,
+    VolumeSelect,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+}
+
+const NAMED_ACTIONS: [(&str, Action); 19] = [
+    ("left", Action::Left),
+    ("right", Action::Right),
+    ("up", Action::Up),
+    ("down", Action::Down),
+    ("action", Action::Button),
+    ("code", Action::Code),
+    ("pause", Action::Pause),
+    ("quit", Action::Quit),
+    ("save", Action::Save),
+    ("load", Action::Load),
+    ("turbo", Action::Turbo),
+    ("rewind", Action::Rewind),
+    ("fullscreen", Action::Fullscreen),
+    ("screenshot", Action::Screenshot),
+    ("replay", Action::Replay),
+    ("volume_select", Action::VolumeSelect),
+    ("volume_up", Action::VolumeUp),
+    ("volume_down", Action::VolumeDown),
+    ("mute", Action::Mute),
+];
+
+const NUM_SLOTS: i8 = 10;
+
+impl Action {
+    fn from_name(name: &str) -> Option<Action> {
+        if let Some(slot) = name.strip_prefix("slot") {
+            return match slot.parse() {
+                Ok(slot) if (0..NUM_SLOTS).contains(&slot) => Some(Action::Slot(slot)),
+                _ => None,
+            };
+        }
+        NAMED_ACTIONS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, action)| *action)
+    }
+
+    fn name(self) -> String {
+        match self {
+            Action::Slot(slot) => format!("slot{}", slot),
+            action => NAMED_ACTIONS
+                .iter()
+                .find(|(_, a)| *a == action)
+                .map(|(n, _)| n.to_string())
+                .expect("Every action except slots is named"),
+        }
+    }
+}
+
+/// Maps keyboard keys to player actions
+pub struct KeyBindings {
+    keys: HashMap<Keycode, Action>,
+}
+
+impl KeyBindings {
+    pub fn new() -> KeyBindings {
+        let mut keys = HashMap::new();
+        for (keycodes, action) in &[
+            (vec![Keycode::Left, Keycode::A], Action::Left),
+            (vec![Keycode::Right, Keycode::D], Action::Right),
+            (vec![Keycode::Up, Keycode::W], Action::Up),
+            (vec![Keycode::Down, Keycode::S], Action::Down),
+            (
+                vec![Keycode::LShift, Keycode::Space, Keycode::Return],
+                Action::Button,
+            ),
+            (vec![Keycode::C], Action::Code),
+            (vec![Keycode::Pause], Action::Pause),
+            (vec![Keycode::Escape], Action::Quit),
+            (vec![Keycode::F5], Action::Save),
+            (vec![Keycode::F7], Action::Load),
+            (vec![Keycode::Tab], Action::Turbo),
+            (vec![Keycode::Backquote], Action::Rewind),
+            (vec![Keycode::F11], Action::Fullscreen),
+            (vec![Keycode::F12], Action::Screenshot),
+            (vec![Keycode::F9], Action::Replay),
+            (vec![Keycode::F2], Action::VolumeSelect),
+            (vec![Keycode::Equals, Keycode::KpPlus], Action::VolumeUp),
+            (vec![Keycode::Minus, Keycode::KpMinus], Action::VolumeDown),
+            (vec![Keycode::M], Action::Mute),
+        ] {
+            for keycode in keycodes {
+                keys.insert(*keycode, *action);
+            }
+        }
+        let slot_keys = [
+            Keycode::Num0,
+            Keycode::Num1,
+            Keycode::Num2,
+            Keycode::Num3,
+            Keycode::Num4,
+            Keycode::Num5,
+            Keycode::Num6,
+            Keycode::Num7,
+            Keycode::Num8,
+            Keycode::Num9,
+        ];
+        for (slot, keycode) in slot_keys.iter().enumerate() {
+            keys.insert(*keycode, Action::Slot(slot as i8));
+        }
+        KeyBindings { keys }
+    }
+
+    /// Default location of the bindings file in the user config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("anotherworld").join("bindings.toml"))
+    }
+
+    /// Loads the bindings file on top of the defaults. Actions that are
+    /// listed in the file replace all default keys for that action.
+    pub fn load(path: &Path) -> Result<KeyBindings> {
+        let mut bindings = KeyBindings::new();
+        if !path.exists() {
+            return Ok(bindings);
+        }
+        info!("Loading key bindings from {}", path.to_string_lossy());
+        let config: BTreeMap<String, Vec<String>> = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        for (name, key_names) in config {
+            let action = match Action::from_name(&name) {
+                Some(action) => action,
+                None => {
+                    warn!("Unknown action in key bindings: {}", name);
+                    continue;
+                }
+            };
+            bindings.keys.retain(|_, a| *a != action);
+            for key_name in key_names {
+                match Keycode::from_name(&key_name) {
+                    Some(keycode) => {
+                        bindings.keys.insert(keycode, action);
+                    }
+                    None => warn!("Unknown key in key bindings: {}", key_name),
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    pub fn action(&self, keycode: Keycode) -> Option<Action> {
+        self.keys.get(&keycode).copied()
+    }
+
+    /// Formats the bindings in the same format as the bindings file
+    pub fn to_toml(&self) -> String {
+        let mut config: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (keycode, action) in self.keys.iter() {
+            config
+                .entry(action.name())
+                .or_default()
+                .push(keycode.name());
+        }
+        for key_names in config.values_mut() {
+            key_names.sort();
+        }
+        toml::to_string(&config).expect("Expected bindings to serialize")
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn action_names_round_trip() {
+        for (name, action) in NAMED_ACTIONS.iter() {
+            assert_eq!(Action::from_name(name), Some(*action));
+            assert_eq!(action.name(), *name);
+        }
+        for slot in 0..NUM_SLOTS {
+            let action = Action::Slot(slot);
+            assert_eq!(Action::from_name(&action.name()), Some(action));
+        }
+    }
+
+    #[test]
+    fn unknown_action_names_are_rejected() {
+        assert_eq!(Action::from_name("jump"), None);
+        assert_eq!(Action::from_name("slot10"), None);
+        assert_eq!(Action::from_name("slot-1"), None);
+        assert_eq!(Action::from_name("slot"), None);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{thread, time};
+
+/// Source of time used by the virtual machine to pace frames.
+///
+/// Timestamps are in game milliseconds, which only match wall-clock
+/// milliseconds when running at 100% speed.
+pub trait Clock {
+    fn get_timestamp(&self) -> u64;
+    fn sleep(&mut self, ms: u64);
+    /// Waits `ms` real milliseconds while the game does not run, such as
+    /// when it is paused. Game time does not advance for it.
+    fn idle(&mut self, ms: u64);
+    fn set_turbo(&mut self, turbo: bool);
+}
+
+/// Converts game milliseconds to a real duration at the given speed in
+/// percent. A speed of 0 means unthrottled.
+fn real_duration(ms: u64, speed: u32) -> Option<time::Duration> {
+    if speed == 0 {
+        None
+    } else {
+        Some(time::Duration::from_micros(ms * 1000 * 100 / speed as u64))
+    }
+}
+
+/// Clock following the wall clock, scaled by a speed factor. When
+/// unthrottled, game time is wall-clock time.
+pub struct SystemClock {
+    start: time::Instant,
+    speed: u32,
+    turbo: bool,
+}
+
+impl SystemClock {
+    pub fn new(speed: u32) -> SystemClock {
+        SystemClock {
+            start: time::Instant::now(),
+            speed,
+            turbo: false,
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn get_timestamp(&self) -> u64 {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        if self.speed == 0 {
+            return elapsed;
+        }
+        elapsed * self.speed as u64 / 100
+    }
+
+    fn sleep(&mut self, ms: u64) {
+        if self.turbo {
+            return;
+        }
+        if let Some(duration) = real_duration(ms, self.speed) {
+            thread::sleep(duration);
+        }
+    }
+
+    fn idle(&mut self, ms: u64) {
+        thread::sleep(time::Duration::from_millis(ms));
+    }
+
+    fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+    }
+}
+
+/// Clock that only advances when the virtual machine sleeps, so every frame
+/// lasts exactly as long as the script asks for. Real time pacing is
+/// optional and does not affect the timestamps.
+pub struct VirtualClock {
+    now: u64,
+    speed: u32,
+    turbo: bool,
+}
+
+impl VirtualClock {
+    pub fn new(speed: u32) -> VirtualClock {
+        VirtualClock {
+            now: 0,
+            speed,
+            turbo: false,
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn get_timestamp(&self) -> u64 {
+        self.now
+    }
+
+    fn sleep(&mut self, ms: u64) {
+        self.now += ms;
+        if self.turbo {
+            return;
+        }
+        if let Some(duration) = real_duration(ms, self.speed) {
+            thread::sleep(duration);
+        }
+    }
+
+    fn idle(&mut self, ms: u64) {
+        thread::sleep(time::Duration::from_millis(ms));
+    }
+
+    fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn real_duration_scales_with_speed() {
+        assert_eq!(
+            real_duration(100, 100),
+            Some(time::Duration::from_millis(100))
+        );
+        assert_eq!(
+            real_duration(100, 50),
+            Some(time::Duration::from_millis(200))
+        );
+        assert_eq!(
+            real_duration(100, 400),
+            Some(time::Duration::from_millis(25))
+        );
+        assert_eq!(real_duration(100, 0), None);
+    }
+
+    #[test]
+    fn virtual_clock_advances_by_sleeps_only() {
+        let mut clock = VirtualClock::new(0);
+        assert_eq!(clock.get_timestamp(), 0);
+        clock.sleep(20);
+        clock.sleep(40);
+        assert_eq!(clock.get_timestamp(), 60);
+        clock.idle(1);
+        assert_eq!(clock.get_timestamp(), 60);
+    }
+
+    #[test]
+    fn turbo_advances_virtual_time_without_waiting() {
+        let mut clock = VirtualClock::new(100);
+        clock.set_turbo(true);
+        let start = time::Instant::now();
+        clock.sleep(60_000);
+        assert_eq!(clock.get_timestamp(), 60_000);
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+
+        clock.set_turbo(false);
+        clock.sleep(10);
+        assert!(start.elapsed() >= time::Duration::from_millis(10));
+        assert_eq!(clock.get_timestamp(), 60_010);
+    }
+
+    #[test]
+    fn unthrottled_system_clock_follows_wall_time() {
+        let mut clock = SystemClock::new(0);
+        clock.idle(15);
+        assert!(clock.get_timestamp() >= 15);
+    }
+}
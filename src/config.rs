@@ -0,0 +1,120 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use toml::Value;
+
+use crate::mixer::Volumes;
+
+/// Settings from the config file. Options given on the command line take
+/// precedence over them.
+pub struct Config {
+    pub volumes: Volumes,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            volumes: Volumes::new(),
+        }
+    }
+
+    /// Default location of the config file in the user config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("anotherworld").join("config.toml"))
+    }
+
+    /// Loads the config file on top of the defaults, a missing file leaves
+    /// all defaults in place
+    pub fn load(path: &Path) -> Result<Config> {
+        let mut config = Config::new();
+        if !path.exists() {
+            return Ok(config);
+        }
+        info!("Loading config from {}", path.to_string_lossy());
+        let value: Value = fs::read_to_string(path)?
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let table = match value {
+            Value::Table(table) => table,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Expected a table")),
+        };
+        for (name, value) in table {
+            match (name.as_str(), value) {
+                ("volume", Value::Table(volume)) => config.load_volumes(volume)?,
+                (name, _) => warn!("Unknown setting in config: {}", name),
+            }
+        }
+        Ok(config)
+    }
+
+    fn load_volumes(&mut self, table: toml::value::Table) -> Result<()> {
+        for (name, value) in table {
+            let volumes = &mut self.volumes;
+            match (name.as_str(), value) {
+                ("master", Value::Integer(level)) => volumes.master = percent(level)?,
+                ("music", Value::Integer(level)) => volumes.music = percent(level)?,
+                ("effects", Value::Integer(level)) => volumes.effects = percent(level)?,
+                ("mute", Value::Boolean(muted)) => volumes.muted = muted,
+                (name, _) => warn!("Unknown volume setting in config: {}", name),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+fn percent(level: i64) -> Result<u8> {
+    if (0..=100).contains(&level) {
+        Ok(level as u8)
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Volume out of range: {}", level),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::temp_path;
+
+    fn load(name: &str, contents: &str) -> Result<Config> {
+        let path = temp_path(&format!("{}.toml", name));
+        fs::write(&path, contents).unwrap();
+        let config = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn volumes_are_loaded_over_the_defaults() {
+        let config = load("volumes", "[volume]\nmusic = 40\nmute = true\n").unwrap();
+        let expected = Volumes {
+            music: 40,
+            muted: true,
+            ..Volumes::new()
+        };
+        assert_eq!(config.volumes, expected);
+    }
+
+    #[test]
+    fn missing_file_gives_the_defaults() {
+        let path = temp_path("no-such-config.toml");
+        assert_eq!(Config::load(&path).unwrap().volumes, Volumes::new());
+    }
+
+    #[test]
+    fn bad_volumes_are_rejected() {
+        assert!(load("range", "[volume]\nmaster = 101\n").is_err());
+        assert!(load("syntax", "[volume\n").is_err());
+    }
+}
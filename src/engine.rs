@@ -1,12 +1,19 @@
+use log::info;
+
+use crate::error::{Error, Result};
 use crate::parts;
 use crate::vm::VirtualMachine;
 
+/// How often input is polled while the game is paused
+const PAUSE_POLL_MS: u64 = 20;
+
 pub struct Engine {
     vm: VirtualMachine,
+    exit_on_music_end: bool,
 }
 
 impl Engine {
-    pub fn new(mut vm: VirtualMachine, part_num: u8) -> Engine {
+    pub fn new(mut vm: VirtualMachine, part_num: u8) -> Result<Engine> {
         let part = match part_num {
             1 => parts::GAME_PART1,
             2 => parts::GAME_PART2,
@@ -18,19 +25,35 @@ impl Engine {
             8 => parts::GAME_PART8,
             9 => parts::GAME_PART9,
             10 => parts::GAME_PART10,
-            n => panic!("Unknown part number: {}", n),
+            n => return Err(Error::UnknownPart(n as u16)),
         };
-        vm.init_for_part(part);
-        Engine { vm }
+        vm.init_for_part(part)?;
+        Ok(Engine {
+            vm,
+            exit_on_music_end: false,
+        })
+    }
+
+    /// Stops the game once the music has played its last order
+    pub fn set_exit_on_music_end(&mut self, exit: bool) {
+        self.exit_on_music_end = exit;
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<()> {
         loop {
-            self.vm.check_thread_requests();
+            self.vm.check_thread_requests()?;
             if !self.vm.update_player_input() {
-                return;
+                return Ok(());
+            }
+            if self.exit_on_music_end && self.vm.has_music_ended() {
+                info!("Music ended, exiting");
+                return Ok(());
+            }
+            if self.vm.is_paused() {
+                self.vm.idle(PAUSE_POLL_MS);
+                continue;
             }
-            self.vm.host_frame();
+            self.vm.host_frame()?;
         }
     }
 }
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors from loading the game resources and running the game
+#[derive(Debug)]
+pub enum Error {
+    /// A bank file could not be opened or read
+    MissingBank {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A packed resource did not unpack to a zero checksum
+    Crc(u32),
+    /// A packed resource is truncated or refers outside of its own data
+    BadPackedData,
+    /// A resource unpacked to a different size than the memlist says
+    SizeMismatch {
+        resource_id: u16,
+        expected: usize,
+        actual: usize,
+    },
+    UnknownPart(u16),
+    /// A resource does not fit in the memory left for scripts and data
+    OutOfMemory {
+        resource_id: u16,
+        size: usize,
+    },
+    /// A music module refers to an instrument that is not a loaded sound
+    BadInstrument(u16),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingBank { path, source } => {
+                write!(f, "Could not read bank {}: {}", path.display(), source)
+            }
+            Error::Crc(crc) => write!(f, "CRC error while unpacking: 0x{:08x}", crc),
+            Error::BadPackedData => write!(f, "Packed data is malformed"),
+            Error::SizeMismatch {
+                resource_id,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Resource 0x{:x} unpacked to {} bytes, expected {}",
+                resource_id, actual, expected
+            ),
+            Error::UnknownPart(part) => write!(f, "Unknown game part: {}", part),
+            Error::OutOfMemory { resource_id, size } => write!(
+                f,
+                "Not enough memory to load resource 0x{:x} of {} bytes",
+                resource_id, size
+            ),
+            Error::BadInstrument(resource_id) => {
+                write!(f, "Bad instrument resource 0x{:x}", resource_id)
+            }
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MissingBank { source, .. } => Some(source),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// For code that deals in I/O errors, such as loading save states
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
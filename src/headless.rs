@@ -0,0 +1,96 @@
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+
+use log::{debug, error, info};
+
+use crate::image::{self, ImageFormat};
+use crate::mixer;
+use crate::player::PlayerInput;
+use crate::sys::Backend;
+use crate::video;
+
+/// Backend that runs without a window or audio device and writes every
+/// presented frame to disk.
+pub struct HeadlessSys {
+    output_dir: PathBuf,
+    format: ImageFormat,
+    frame_limit: Option<u64>,
+    frame_count: u64,
+    palette: video::Palette,
+    player_input: PlayerInput,
+    width: usize,
+    height: usize,
+}
+
+impl HeadlessSys {
+    pub fn new(
+        output_dir: PathBuf,
+        format: ImageFormat,
+        frame_limit: Option<u64>,
+        width: usize,
+        height: usize,
+    ) -> Result<HeadlessSys> {
+        fs::create_dir_all(&output_dir)?;
+        Ok(HeadlessSys {
+            output_dir,
+            format,
+            frame_limit,
+            frame_count: 0,
+            palette: video::Palette::new(),
+            player_input: PlayerInput::new(),
+            width,
+            height,
+        })
+    }
+}
+
+impl Backend for HeadlessSys {
+    fn set_palette(&mut self, palette: &video::Palette) {
+        debug!("set_palette()");
+        self.palette = *palette;
+    }
+
+    fn update_display(&mut self, page: &video::Page) {
+        let file_name = format!("frame_{:06}.{}", self.frame_count, self.format.extension());
+        let path = self.output_dir.join(file_name);
+        debug!("update_display() {}", path.to_string_lossy());
+        if let Err(e) = image::write_image(
+            &path,
+            self.format,
+            &page.data,
+            self.width,
+            self.height,
+            &self.palette,
+        ) {
+            error!("Could not write {}: {}", path.to_string_lossy(), e);
+            self.player_input.quit = true;
+        }
+        self.frame_count += 1;
+        if let Some(frame_limit) = self.frame_limit {
+            if self.frame_count >= frame_limit {
+                info!("Reached frame limit of {}", frame_limit);
+                self.player_input.quit = true;
+            }
+        }
+    }
+
+    fn process_events(&mut self) -> PlayerInput {
+        self.player_input
+    }
+
+    fn set_text_input(&mut self, _enabled: bool) {}
+
+    fn start_audio(&mut self, audio: mixer::MixerAudio) -> Option<mixer::MixerAudio> {
+        debug!("Headless backend has no audio output");
+        Some(audio)
+    }
+
+    fn stop_audio(&mut self) -> Option<mixer::MixerAudio> {
+        None
+    }
+
+    fn show_message(&mut self, message: &str) {
+        debug!("Message: {}", message);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufWriter, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::video::Palette;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "ppm" => Ok(ImageFormat::Ppm),
+            _ => Err(format!("Unknown image format: {}", s)),
+        }
+    }
+}
+
+pub fn write_image(
+    path: &Path,
+    format: ImageFormat,
+    data: &[u8],
+    width: usize,
+    height: usize,
+    palette: &Palette,
+) -> Result<()> {
+    match format {
+        ImageFormat::Png => write_png(path, data, width, height, palette),
+        ImageFormat::Ppm => write_ppm(path, data, width, height, palette),
+    }
+}
+
+/// Writes a page buffer as an 8-bit indexed PNG using the given palette.
+pub fn write_png(
+    path: &Path,
+    data: &[u8],
+    width: usize,
+    height: usize,
+    palette: &Palette,
+) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .entries
+            .iter()
+            .flat_map(|c| vec![c.r, c.g, c.b])
+            .collect(),
+    );
+    let mut writer = encoder.write_header()?;
+    let indices: Vec<u8> = data[..width * height].iter().map(|i| i & 0x0f).collect();
+    writer.write_image_data(&indices)?;
+    Ok(())
+}
+
+/// Writes a page buffer as a binary PPM, expanding the palette indices to RGB.
+pub fn write_ppm(
+    path: &Path,
+    data: &[u8],
+    width: usize,
+    height: usize,
+    palette: &Palette,
+) -> Result<()> {
+    write_rgb_ppm(path, &to_rgb(data, width, height, palette), width, height)
+}
+
+/// Writes already expanded RGB pixels, for frames whose palette is gone
+pub fn write_rgb_image(
+    path: &Path,
+    format: ImageFormat,
+    rgb: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<()> {
+    match format {
+        ImageFormat::Png => {
+            let file = BufWriter::new(File::create(path)?);
+            let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+            encoder.set_color(png::ColorType::RGB);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(rgb)?;
+            Ok(())
+        }
+        ImageFormat::Ppm => write_rgb_ppm(path, rgb, width, height),
+    }
+}
+
+fn write_rgb_ppm(path: &Path, rgb: &[u8], width: usize, height: usize) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", width, height)?;
+    file.write_all(rgb)?;
+    file.flush()
+}
+
+pub fn to_rgb(data: &[u8], width: usize, height: usize, palette: &Palette) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for i in data[..width * height].iter() {
+        let c = palette.entries[(i & 0x0f) as usize];
+        rgb.extend_from_slice(&[c.r, c.g, c.b]);
+    }
+    rgb
+}
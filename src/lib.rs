@@ -1,6 +1,20 @@
 pub mod bank;
+pub mod bindings;
+pub mod clock;
+pub mod config;
 pub mod engine;
+pub mod error;
+pub mod headless;
+pub mod image;
+pub mod manifest;
+pub mod movie;
+pub mod record;
+pub mod release;
+pub mod replay;
 pub mod resource;
+pub mod rewind;
+pub mod screenshot;
+pub mod state;
 pub mod sys;
 pub mod video;
 pub mod vm;
@@ -9,7 +23,9 @@ mod font;
 pub mod mixer;
 mod opcode;
 mod parts;
-mod player;
-mod sfxplayer;
+pub mod player;
+pub mod sfxplayer;
 mod strings;
+#[cfg(test)]
+mod testutil;
 mod util;
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Result};
+use std::path::Path;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::bank::Bank;
+use crate::error;
+use crate::resource::{self, AssetPlatform, MemEntry, Resource};
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Describes a set of extracted resources, in memlist order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub platform: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// The memlist fields of one resource and the file holding its unpacked data
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub index: usize,
+    /// Not set for entries that are in no bank
+    pub file: Option<String>,
+    pub state: u8,
+    pub entry_type: String,
+    pub buf_ptr: u16,
+    pub rank_num: u8,
+    pub bank_id: u8,
+    pub bank_offset: u32,
+    pub packed_size: u16,
+    pub size: u16,
+    pub unk4: u16,
+    pub unkc: u16,
+    pub unk10: u16,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> Result<Manifest> {
+        let reader = BufReader::new(File::open(dir.join(MANIFEST_NAME))?);
+        serde_json::from_reader(reader).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let writer = BufWriter::new(File::create(dir.join(MANIFEST_NAME))?);
+        serde_json::to_writer_pretty(writer, self).map_err(Error::other)
+    }
+}
+
+/// Unpacks every resource to its own file in `dir` and writes the manifest
+/// next to them
+pub fn extract(res: &Resource, dir: &Path) -> error::Result<Manifest> {
+    fs::create_dir_all(dir)?;
+    let mut entries = Vec::new();
+    for (index, entry) in res.mem_list.iter().enumerate() {
+        let file = match res.read_entry_data(index as u16)? {
+            Some(data) => {
+                let name = format!("{:03}_{}.bin", index, entry.entry_type.name());
+                fs::write(dir.join(&name), data)?;
+                Some(name)
+            }
+            None => None,
+        };
+        entries.push(entry.manifest_entry(index, file));
+    }
+    let manifest = Manifest {
+        platform: res.asset_platform.name().to_string(),
+        entries,
+    };
+    manifest.save(dir)?;
+    info!(
+        "Extracted {} resources to {}",
+        manifest.entries.len(),
+        dir.to_string_lossy()
+    );
+    Ok(manifest)
+}
+
+/// Builds a PC style `Memlist.bin` and bank files in `output_dir` from the
+/// files and manifest in `dir`. Every resource stays in its bank and is
+/// compressed when that makes it smaller.
+pub fn pack(dir: &Path, output_dir: &Path) -> error::Result<()> {
+    let mut manifest = Manifest::load(dir)?;
+    if manifest.platform != AssetPlatform::PC.name() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Only PC resources can be packed, the manifest is for {}",
+                manifest.platform
+            ),
+        )
+        .into());
+    }
+    manifest.entries.sort_by_key(|entry| entry.index);
+    fs::create_dir_all(output_dir)?;
+    let mut banks: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+    let mut mem_list = Vec::new();
+    for mut entry in manifest.entries {
+        // Scripts refer to resources by their position in the memlist
+        if entry.index != mem_list.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expected entry {}, found entry {}",
+                    mem_list.len(),
+                    entry.index
+                ),
+            )
+            .into());
+        }
+        if entry.file.is_none() && entry.bank_id != 0 {
+            // Its offset and size would point into data that is not packed
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Entry {} has a bank but no file", entry.index),
+            )
+            .into());
+        }
+        if let Some(ref file) = entry.file {
+            if entry.bank_id == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Entry {} has a file but no bank", entry.index),
+                )
+                .into());
+            }
+            let data = fs::read(dir.join(file))?;
+            if data.len() > u16::MAX as usize {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is too large for a resource", file),
+                )
+                .into());
+            }
+            entry.size = data.len() as u16;
+            let bank = Bank::pack(data);
+            let bank_data = banks.entry(entry.bank_id).or_default();
+            entry.bank_offset = bank_data.len() as u32;
+            entry.packed_size = bank.stored_data().len() as u16;
+            bank_data.extend_from_slice(bank.stored_data());
+        }
+        mem_list.push(MemEntry::from_manifest_entry(&entry)?);
+    }
+
+    let mut writer = BufWriter::new(File::create(output_dir.join("Memlist.bin"))?);
+    resource::write_memlist(&mem_list, &mut writer)?;
+    writer.flush()?;
+    for (bank_id, data) in banks.iter() {
+        fs::write(output_dir.join(format!("Bank{:02x}", bank_id)), data)?;
+    }
+    info!(
+        "Packed {} resources into {} banks in {}",
+        mem_list.len(),
+        banks.len(),
+        output_dir.to_string_lossy()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::resource::MemlistReader;
+    use crate::testutil::temp_path;
+
+    fn entry(index: usize, entry_type: &str, bank_id: u8, file: Option<&str>) -> ManifestEntry {
+        ManifestEntry {
+            index,
+            file: file.map(str::to_string),
+            state: 0,
+            entry_type: entry_type.to_string(),
+            buf_ptr: 0,
+            rank_num: index as u8,
+            bank_id,
+            bank_offset: 0,
+            packed_size: 0,
+            size: 0,
+            unk4: 0,
+            unkc: 0x10,
+            unk10: 0x20,
+        }
+    }
+
+    /// Writes a manifest with one packable, one unpackable and one missing
+    /// resource, listed out of order. Returns the data of each entry.
+    fn write_extracted(dir: &Path, platform: &str) -> Vec<Option<Vec<u8>>> {
+        fs::create_dir_all(dir).unwrap();
+        let music: Vec<u8> = b"music ".iter().cycle().take(3000).cloned().collect();
+        let script = vec![1, 2, 3, 4, 5];
+        fs::write(dir.join("music.bin"), &music).unwrap();
+        fs::write(dir.join("script.bin"), &script).unwrap();
+        let manifest = Manifest {
+            platform: platform.to_string(),
+            entries: vec![
+                entry(2, "bytecode", 2, Some("script.bin")),
+                entry(0, "music", 1, Some("music.bin")),
+                entry(1, "sound", 0, None),
+            ],
+        };
+        manifest.save(dir).unwrap();
+        vec![Some(music), None, Some(script)]
+    }
+
+    #[test]
+    fn packed_resources_read_back() {
+        let dir = temp_path("extracted");
+        let output_dir = temp_path("packed");
+        let data = write_extracted(&dir, "pc");
+        pack(&dir, &output_dir).unwrap();
+
+        let res = MemlistReader::new(output_dir.clone(), AssetPlatform::PC)
+            .read_memlist()
+            .unwrap();
+        assert_eq!(res.mem_list.len(), 3);
+        for (index, expected) in data.iter().enumerate() {
+            assert_eq!(&res.read_entry_data(index as u16).unwrap(), expected);
+            let entry = res.mem_list[index].manifest_entry(index, None);
+            assert_eq!(entry.rank_num, index as u8);
+            assert_eq!((entry.unkc, entry.unk10), (0x10, 0x20));
+        }
+        assert!(res.mem_list[0].manifest_entry(0, None).packed_size < 3000);
+        assert_eq!(res.mem_list[2].manifest_entry(2, None).packed_size, 5);
+
+        // Extracting and packing again gives the same files
+        let again_dir = temp_path("extracted-again");
+        let again_output_dir = temp_path("packed-again");
+        extract(&res, &again_dir).unwrap();
+        pack(&again_dir, &again_output_dir).unwrap();
+        for name in &["Memlist.bin", "Bank01", "Bank02"] {
+            assert_eq!(
+                fs::read(output_dir.join(name)).unwrap(),
+                fs::read(again_output_dir.join(name)).unwrap()
+            );
+        }
+
+        for dir in &[dir, output_dir, again_dir, again_output_dir] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn other_platforms_are_rejected() {
+        let dir = temp_path("amiga");
+        write_extracted(&dir, "amiga");
+        assert!(pack(&dir, &dir.join("packed")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_entries_are_rejected() {
+        let dir = temp_path("gap");
+        write_extracted(&dir, "pc");
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.entries[2].index = 3;
+        manifest.save(&dir).unwrap();
+        assert!(pack(&dir, &dir.join("packed")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn banked_entries_without_a_file_are_rejected() {
+        let dir = temp_path("no-file");
+        write_extracted(&dir, "pc");
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.entries[2].bank_id = 1;
+        manifest.entries[2].bank_offset = 0x100;
+        manifest.entries[2].packed_size = 10;
+        manifest.save(&dir).unwrap();
+        assert!(pack(&dir, &dir.join("packed")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
-use std::sync::{Arc, RwLock};
-use std::thread::sleep;
-use std::time::Duration;
+use std::cmp;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
-use log::{debug, trace};
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use log::debug;
 use sdl2::audio::AudioCallback;
 
-use crate::sfxplayer::SfxPattern;
+use crate::sfxplayer::{SfxPattern, SfxSequencer};
 
 pub const FREQUENCE_TABLE: [u16; 40] = [
     0x0CFF, 0x0DC3, 0x0E91, 0x0F6F, 0x1056, 0x114E, 0x1259, 0x136C, 0x149F, 0x15D9, 0x1726, 0x1888,
@@ -16,16 +21,117 @@ pub const FREQUENCE_TABLE: [u16; 40] = [
 
 const NUM_CHANNELS: usize = 4;
 
-pub const SOUND_SAMPLE_RATE: u32 = 22050;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
 
-fn add_clamp(a: i16, b: i16) -> i8 {
-    let mut add = a + b;
-    if add < -128 {
-        add = -128;
-    } else if add > 127 {
-        add = 127;
+/// Output is interleaved stereo, left first
+pub const OUTPUT_CHANNELS: u8 = 2;
+
+const DEFAULT_STEREO_SEPARATION: u8 = 100;
+
+const FRAC_BITS: u32 = 16;
+const FRAC_MASK: u32 = (1 << FRAC_BITS) - 1;
+
+/// Zero crossings on each side of the band-limited step
+const BLEP_ZERO_CROSSINGS: usize = 8;
+const BLEP_LEN: usize = 2 * BLEP_ZERO_CROSSINGS;
+/// Table entries per output sample
+const BLEP_PHASES: usize = 64;
+/// Cutoff relative to the output Nyquist frequency
+const BLEP_CUTOFF: f64 = 0.9;
+
+lazy_static! {
+    /// Blackman windowed, integrated sinc: a step from 0 to 1 without
+    /// frequencies above the cutoff, centered in the table.
+    static ref BLEP_TABLE: Vec<f32> = {
+        let len = BLEP_LEN * BLEP_PHASES;
+        let impulse: Vec<f64> = (0..=len)
+            .map(|i| {
+                let x = i as f64 / BLEP_PHASES as f64 - BLEP_ZERO_CROSSINGS as f64;
+                let t = BLEP_CUTOFF * x;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                let w = 2.0 * PI * i as f64 / len as f64;
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                sinc * window
+            })
+            .collect();
+        let mut sum = 0.0;
+        let mut step = Vec::with_capacity(impulse.len());
+        for (i, h) in impulse.iter().enumerate() {
+            if i > 0 {
+                sum += (impulse[i - 1] + h) / 2.0;
+            }
+            step.push(sum);
+        }
+        step.iter().map(|s| (s / sum) as f32).collect()
+    };
+}
+
+/// How channel samples are resampled to the output rate
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Resampling {
+    Nearest,
+    Linear,
+    /// Holds each sample like Paula does, with band-limited steps between
+    /// samples so that the hold does not alias
+    BandLimited,
+}
+
+/// Whether a channel plays a note of the music or a sound effect, so that
+/// both can have their own volume
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SoundKind {
+    Music,
+    Effect,
+}
+
+/// Volume levels in percent
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Volumes {
+    pub master: u8,
+    pub music: u8,
+    pub effects: u8,
+    pub muted: bool,
+}
+
+impl Volumes {
+    pub fn new() -> Volumes {
+        Volumes {
+            master: 100,
+            music: 100,
+            effects: 100,
+            muted: false,
+        }
+    }
+
+    fn gain(&self, kind: SoundKind) -> f32 {
+        if self.muted {
+            return 0.0;
+        }
+        let level = match kind {
+            SoundKind::Music => self.music,
+            SoundKind::Effect => self.effects,
+        };
+        cmp::min(self.master, 100) as f32 * cmp::min(level, 100) as f32 / 10000.0
+    }
+}
+
+impl Default for Volumes {
+    fn default() -> Volumes {
+        Volumes::new()
+    }
+}
+
+impl FromStr for Resampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(Resampling::Nearest),
+            "linear" => Ok(Resampling::Linear),
+            "blep" | "band-limited" => Ok(Resampling::BandLimited),
+            _ => Err(format!("Unknown resampling mode: {}", s)),
+        }
     }
-    add as i8
 }
 
 pub struct MixerChunk {
@@ -54,16 +160,302 @@ impl MixerChunk {
             loop_pos: pattern.loop_pos,
         }
     }
+
+    /// Index after `index`, or `None` past the end of a non-looping sample
+    fn next_index(&self, index: usize) -> Option<usize> {
+        if self.loop_len != 0 {
+            if index + 1 >= self.loop_pos + self.loop_len {
+                Some(self.loop_pos)
+            } else {
+                Some(index + 1)
+            }
+        } else if index + 1 >= self.len {
+            None
+        } else {
+            Some(index + 1)
+        }
+    }
+
+    fn sample(&self, index: usize) -> f32 {
+        self.data.get(index).map_or(0.0, |b| *b as i8 as f32)
+    }
+}
+
+/// Longest queue of commands the audio thread has not picked up yet
+const COMMAND_QUEUE_LEN: usize = 256;
+
+/// Music position in `music_position`, 0 while no music plays
+const MUSIC_PLAYING: u32 = 1 << 31;
+
+enum MixerCommand {
+    PlayChannel {
+        channel: u8,
+        chunk: MixerChunk,
+        frequency: u16,
+        volume: u8,
+        kind: SoundKind,
+    },
+    StopChannel(u8),
+    SetChannelVolume(u8, u8),
+    StopAll,
+    PlayMusic(Box<SfxSequencer>),
+    StopMusic,
+    SetMusicDelay(u16),
+    SetStereoSeparation(u8),
+    SetSampleRate(u32),
+    SetResampling(Resampling),
+    SetVolumes(Volumes),
+    SetPaused(bool),
+}
+
+fn pack_music_position(position: Option<(u8, usize)>) -> u32 {
+    match position {
+        Some((order, pos)) => MUSIC_PLAYING | (order as u32) << 16 | pos as u32 & 0xffff,
+        None => 0,
+    }
+}
+
+impl MixerCommand {
+    /// Whether running `self` after `earlier` leaves nothing of `earlier`
+    fn supersedes(&self, earlier: &MixerCommand) -> bool {
+        match self {
+            MixerCommand::StopAll => matches!(
+                earlier,
+                MixerCommand::PlayChannel { .. }
+                    | MixerCommand::StopChannel(_)
+                    | MixerCommand::SetChannelVolume(..)
+                    | MixerCommand::StopAll
+            ),
+            MixerCommand::StopMusic => matches!(
+                earlier,
+                MixerCommand::PlayMusic(_)
+                    | MixerCommand::SetMusicDelay(_)
+                    | MixerCommand::StopMusic
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Controls the mixer from the VM. Commands are put on a lock-free queue
+/// that the audio thread drains at the start of every callback, so neither
+/// side ever waits for the other.
+pub struct MixerHandle {
+    commands: Arc<ArrayQueue<MixerCommand>>,
+    /// Commands that did not fit in the queue, sent first on the next try
+    overflow: VecDeque<MixerCommand>,
+    music_position: Arc<AtomicU32>,
+}
+
+impl MixerHandle {
+    /// Creates a mixer, returning the handle to control it and the audio
+    /// callback that plays it
+    pub fn new() -> (MixerHandle, MixerAudio) {
+        let commands = Arc::new(ArrayQueue::new(COMMAND_QUEUE_LEN));
+        let music_position = Arc::new(AtomicU32::new(0));
+        let handle = MixerHandle {
+            commands: commands.clone(),
+            overflow: VecDeque::new(),
+            music_position: music_position.clone(),
+        };
+        let audio = MixerAudio {
+            mixer: Mixer::new(music_position),
+            commands,
+        };
+        (handle, audio)
+    }
+
+    fn send(&mut self, command: MixerCommand) {
+        self.flush();
+        if !self.overflow.is_empty() {
+            self.hold(command);
+        } else if let Err(command) = self.commands.push(command) {
+            debug!("Mixer command queue is full, holding commands");
+            self.hold(command);
+        }
+    }
+
+    /// Keeps a command until the queue has room, dropping the held commands
+    /// that it makes pointless so that stopping never has to wait behind them
+    fn hold(&mut self, command: MixerCommand) {
+        self.overflow.retain(|earlier| !command.supersedes(earlier));
+        self.overflow.push_back(command);
+    }
+
+    /// Moves held commands to the queue as far as it has room. Called once
+    /// per frame so that they go out even when no new command is sent.
+    pub fn flush(&mut self) {
+        while let Some(command) = self.overflow.pop_front() {
+            if let Err(command) = self.commands.push(command) {
+                self.overflow.push_front(command);
+                break;
+            }
+        }
+    }
+
+    pub fn play_channel(
+        &mut self,
+        channel: u8,
+        chunk: MixerChunk,
+        frequency: u16,
+        volume: u8,
+        kind: SoundKind,
+    ) {
+        self.send(MixerCommand::PlayChannel {
+            channel,
+            chunk,
+            frequency,
+            volume,
+            kind,
+        });
+    }
+
+    pub fn stop_channel(&mut self, channel: u8) {
+        self.send(MixerCommand::StopChannel(channel));
+    }
+
+    pub fn set_channel_volume(&mut self, channel: u8, volume: u8) {
+        self.send(MixerCommand::SetChannelVolume(channel, volume));
+    }
+
+    pub fn stop_all(&mut self) {
+        self.send(MixerCommand::StopAll);
+    }
+
+    pub fn play_music(&mut self, sequencer: SfxSequencer) {
+        // Set right away, so that the position is right before the audio
+        // thread gets to the command
+        let position = pack_music_position(Some(sequencer.position()));
+        self.music_position.store(position, Ordering::Release);
+        self.send(MixerCommand::PlayMusic(Box::new(sequencer)));
+    }
+
+    pub fn stop_music(&mut self) {
+        self.music_position.store(0, Ordering::Release);
+        self.send(MixerCommand::StopMusic);
+    }
+
+    pub fn set_music_delay(&mut self, delay: u16) {
+        self.send(MixerCommand::SetMusicDelay(delay));
+    }
+
+    /// Returns the order and row offset of the music being played
+    pub fn music_position(&self) -> Option<(u8, usize)> {
+        let position = self.music_position.load(Ordering::Acquire);
+        if position & MUSIC_PLAYING == 0 {
+            return None;
+        }
+        Some(((position >> 16) as u8, (position & 0xffff) as usize))
+    }
+
+    /// Sets how far channels are panned apart, from 0 (mono) to 100
+    /// (hard left and right like the Amiga)
+    pub fn set_stereo_separation(&mut self, percent: u8) {
+        self.send(MixerCommand::SetStereoSeparation(percent));
+    }
+
+    /// Sets the mixing rate, for when no audio device decides it
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.send(MixerCommand::SetSampleRate(sample_rate));
+    }
+
+    pub fn set_resampling(&mut self, resampling: Resampling) {
+        self.send(MixerCommand::SetResampling(resampling));
+    }
+
+    pub fn set_volumes(&mut self, volumes: Volumes) {
+        self.send(MixerCommand::SetVolumes(volumes));
+    }
+
+    /// Holds the music and all channels where they are, outputting silence
+    /// until resumed
+    pub fn set_paused(&mut self, paused: bool) {
+        self.send(MixerCommand::SetPaused(paused));
+    }
 }
 
+/// Mixer state, only ever touched by the thread that renders audio
 pub struct Mixer {
     channels: [Option<MixerChannel>; NUM_CHANNELS],
+    stereo_separation: u8,
+    sample_rate: u32,
+    resampling: Resampling,
+    volumes: Volumes,
+    paused: bool,
+    music: Option<SfxSequencer>,
+    music_position: Arc<AtomicU32>,
 }
 
 impl Mixer {
-    pub fn new() -> Mixer {
+    fn new(music_position: Arc<AtomicU32>) -> Mixer {
         Mixer {
             channels: [None, None, None, None],
+            stereo_separation: DEFAULT_STEREO_SEPARATION,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            resampling: Resampling::Linear,
+            volumes: Volumes::new(),
+            paused: false,
+            music: None,
+            music_position,
+        }
+    }
+
+    fn execute(&mut self, command: MixerCommand) {
+        match command {
+            MixerCommand::PlayChannel {
+                channel,
+                chunk,
+                frequency,
+                volume,
+                kind,
+            } => self.play_channel(channel, chunk, frequency, volume, kind),
+            MixerCommand::StopChannel(channel) => self.stop_channel(channel),
+            MixerCommand::SetChannelVolume(channel, volume) => {
+                if let Some(ref mut channel) = self.channels[channel as usize] {
+                    channel.volume = volume;
+                }
+            }
+            MixerCommand::StopAll => {
+                for channel in self.channels.iter_mut() {
+                    channel.take();
+                }
+            }
+            MixerCommand::PlayMusic(sequencer) => {
+                self.music = Some(*sequencer);
+                self.update_music_position();
+            }
+            MixerCommand::StopMusic => {
+                self.music = None;
+                self.update_music_position();
+            }
+            MixerCommand::SetMusicDelay(delay) => {
+                if let Some(sequencer) = self.music.as_mut() {
+                    sequencer.set_delay(delay);
+                }
+            }
+            MixerCommand::SetStereoSeparation(percent) => {
+                self.stereo_separation = cmp::min(percent, 100);
+            }
+            MixerCommand::SetSampleRate(sample_rate) => self.sample_rate = sample_rate,
+            MixerCommand::SetResampling(resampling) => self.resampling = resampling,
+            MixerCommand::SetVolumes(volumes) => self.volumes = volumes,
+            MixerCommand::SetPaused(paused) => self.paused = paused,
+        }
+    }
+
+    fn update_music_position(&self) {
+        let position = pack_music_position(self.music.as_ref().map(|s| s.position()));
+        self.music_position.store(position, Ordering::Release);
+    }
+
+    /// Gains out of 100 for the left and right side of a channel. Paula
+    /// outputs channels 0 and 3 on the left, 1 and 2 on the right.
+    fn channel_gains(&self, channel: usize) -> (f32, f32) {
+        let near = (100 + self.stereo_separation as i32) as f32 / 100.0;
+        let far = (100 - self.stereo_separation as i32) as f32 / 100.0;
+        match channel {
+            0 | 3 => (near, far),
+            _ => (far, near),
         }
     }
 
@@ -73,97 +465,377 @@ impl Mixer {
         mixer_chunk: MixerChunk,
         frequency: u16,
         volume: u8,
+        kind: SoundKind,
     ) {
         //debug!("mixer chunk {}, {}, {}", mixer_chunk.len, mixer_chunk.loop_len, mixer_chunk.loop_pos);
-        self.channels[channel as usize] =
-            Some(MixerChannel::new(volume, mixer_chunk, frequency.into()));
+        self.channels[channel as usize] = Some(MixerChannel::new(
+            volume,
+            mixer_chunk,
+            frequency.into(),
+            kind,
+        ));
     }
 
     pub fn stop_channel(&mut self, channel: u8) {
         self.channels[channel as usize].take();
     }
 
-    pub fn _set_channel_volume(&mut self, channel: u8, volume: u8) {
-        if let Some(ref mut channel) = self.channels[channel as usize] {
-            channel.volume = volume;
+    /// Mixes interleaved stereo frames. Channels are summed as floats and
+    /// scaled so that four channels at full volume just fit in 16 bits.
+    fn mix(&mut self, out: &mut [i16]) {
+        if self.paused {
+            for s in out.iter_mut() {
+                *s = 0;
+            }
+            return;
         }
-    }
+        let mut gains = [(0.0, 0.0); NUM_CHANNELS];
+        for (i, gain) in gains.iter_mut().enumerate() {
+            *gain = self.channel_gains(i);
+        }
+        let music_gain = self.volumes.gain(SoundKind::Music);
+        let effect_gain = self.volumes.gain(SoundKind::Effect);
+        let sample_rate = self.sample_rate;
+        let resampling = self.resampling;
 
-    pub fn stop_all(&mut self) {
-        for channel in self.channels.iter_mut() {
-            channel.take();
+        for frame in out.chunks_mut(OUTPUT_CHANNELS as usize) {
+            let row_due = match self.music.as_mut() {
+                Some(sequencer) => sequencer.advance(sample_rate),
+                None => false,
+            };
+            if row_due {
+                if let Some(mut sequencer) = self.music.take() {
+                    if sequencer.handle_events(self) {
+                        self.music = Some(sequencer);
+                    }
+                    self.update_music_position();
+                }
+            }
+
+            let mut left = 0.0;
+            let mut right = 0.0;
+            for (chan_num, ch) in self.channels.iter_mut().enumerate() {
+                if let Some(channel) = ch {
+                    match channel.next_sample(sample_rate, resampling) {
+                        Some(s) => {
+                            let s = match channel.kind {
+                                SoundKind::Music => s * music_gain,
+                                SoundKind::Effect => s * effect_gain,
+                            };
+                            left += s * gains[chan_num].0;
+                            right += s * gains[chan_num].1;
+                        }
+                        None => {
+                            ch.take();
+                        }
+                    }
+                }
+            }
+            frame[0] = to_i16(left);
+            frame[1] = to_i16(right);
         }
     }
 }
 
-#[derive(Clone)]
-pub struct MixerAudio(pub Arc<RwLock<Mixer>>);
+fn to_i16(s: f32) -> i16 {
+    (s * 64.0).max(i16::MIN as f32).min(i16::MAX as f32) as i16
+}
+
+/// The audio callback, which owns the mixer
+pub struct MixerAudio {
+    mixer: Mixer,
+    commands: Arc<ArrayQueue<MixerCommand>>,
+}
+
+impl MixerAudio {
+    pub fn sample_rate(&self) -> u32 {
+        self.mixer.sample_rate
+    }
+
+    /// Sets the rate the audio device actually runs at
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        debug!("Mixing at {} Hz", sample_rate);
+        self.mixer.sample_rate = sample_rate;
+    }
+
+    /// Applies the commands sent so far, without waiting for more
+    pub fn process_commands(&mut self) {
+        while let Some(command) = self.commands.pop() {
+            self.mixer.execute(command);
+        }
+    }
+}
 
 impl AudioCallback for MixerAudio {
-    type Channel = i8;
+    type Channel = i16;
 
-    fn callback(&mut self, out: &mut [i8]) {
-        trace!("MixerAudio::callback()");
-        let mut write_guard = loop {
-            if let Ok(write_guard) = self.0.write() {
-                break write_guard;
-            }
-            sleep(Duration::from_millis(10));
-        };
-        for s in out.iter_mut() {
-            *s = 0;
-        }
-
-        for (chan_num, ch) in write_guard.channels.iter_mut().enumerate() {
-            if let Some(ref mut channel) = ch {
-                for s in out.iter_mut() {
-                    let ilc = (channel.chunk_pos & 0xff) as i16;
-                    let p1 = channel.chunk_pos >> 8;
-                    channel.chunk_pos += channel.chunk_inc;
-
-                    let p2 = if channel.chunk.loop_len != 0 {
-                        if p1 == channel.chunk.loop_pos + channel.chunk.loop_len - 1 {
-                            debug!("Looping sample on channel {}", chan_num);
-                            channel.chunk_pos = channel.chunk.loop_pos;
-                            channel.chunk.loop_pos
-                        } else {
-                            p1 + 1
-                        }
-                    } else if channel.chunk.len == 0 || p1 == channel.chunk.len - 1 {
-                        debug!("Stopping sample on channel {}", chan_num);
-                        ch.take();
-                        break;
-                    } else {
-                        p1 + 1
-                    };
-                    assert!(p1 < channel.chunk.data.len());
-                    assert!(p2 < channel.chunk.data.len());
-                    let b1 = channel.chunk.data[p1] as i8;
-                    let b2 = channel.chunk.data[p2] as i8;
-                    let b = ((b1 as i16 * (0xff - ilc) + b2 as i16 * ilc) >> 8) as i8;
-
-                    *s = add_clamp(*s as i16, b as i16 * channel.volume as i16 / 0x40);
-                    //debug!("j: {}, p1: {}, b1: {}, p2: {}, b2: {}, b: {}, sample: {}", j, p1, b1, p2, b2, b, *s);
-                }
-            }
+    fn callback(&mut self, out: &mut [i16]) {
+        self.process_commands();
+        self.mixer.mix(out);
+    }
+}
+
+/// Pulls the mixer by game time instead of from an audio device, so that
+/// music keeps playing when there is no audio output
+pub struct OfflineOutput {
+    audio: MixerAudio,
+    start: Option<u64>,
+    samples: u64,
+    buffer: Vec<i16>,
+}
+
+impl OfflineOutput {
+    pub fn new(mut audio: MixerAudio) -> OfflineOutput {
+        // Picks up the sample rate if it was just set
+        audio.process_commands();
+        OfflineOutput {
+            audio,
+            start: None,
+            samples: 0,
+            buffer: Vec::new(),
         }
     }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.audio.sample_rate()
+    }
+
+    /// Hands the mixer back, for playing it on an audio device
+    pub fn into_audio(self) -> MixerAudio {
+        self.audio
+    }
+
+    /// Number of frames rendered so far
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Renders the interleaved frames up to clock time `timestamp`. The
+    /// first call marks the start and renders nothing.
+    pub fn render_until(&mut self, timestamp: u64) -> &[i16] {
+        let elapsed = timestamp.saturating_sub(*self.start.get_or_insert(timestamp));
+        let target = elapsed * self.sample_rate() as u64 / 1000;
+        let frames = target.saturating_sub(self.samples) as usize;
+        self.buffer.clear();
+        self.buffer.resize(frames * OUTPUT_CHANNELS as usize, 0);
+        if frames > 0 {
+            self.audio.callback(&mut self.buffer);
+            self.samples = target;
+        }
+        &self.buffer
+    }
 }
 
 struct MixerChannel {
     volume: u8,
+    kind: SoundKind,
     chunk: MixerChunk,
-    chunk_pos: usize,
-    chunk_inc: usize,
+    index: usize,
+    /// Position between `index` and the next sample, in 1 / 2^FRAC_BITS
+    frac: u32,
+    frequency: u32,
+    /// Sample that is being held, for band-limited resampling
+    level: f32,
+    /// Pending corrections of the steps between held samples
+    blep: [f32; BLEP_LEN],
+    blep_pos: usize,
 }
 
 impl MixerChannel {
-    pub fn new(volume: u8, chunk: MixerChunk, frequency: u32) -> MixerChannel {
-        MixerChannel {
+    pub fn new(volume: u8, chunk: MixerChunk, frequency: u32, kind: SoundKind) -> MixerChannel {
+        let first = chunk.sample(0);
+        let mut channel = MixerChannel {
             volume,
+            kind,
             chunk,
-            chunk_pos: 0,
-            chunk_inc: ((frequency << 8) / SOUND_SAMPLE_RATE) as usize,
+            index: 0,
+            frac: 0,
+            frequency,
+            level: 0.0,
+            blep: [0.0; BLEP_LEN],
+            blep_pos: 0,
+        };
+        channel.add_step(first, 0.0);
+        channel
+    }
+
+    /// Returns the next output sample scaled by the channel volume, or
+    /// `None` once a non-looping sample has ended
+    fn next_sample(&mut self, sample_rate: u32, resampling: Resampling) -> Option<f32> {
+        if self.chunk.len == 0 && self.chunk.loop_len == 0 {
+            return None;
+        }
+        let s = match resampling {
+            Resampling::Nearest => self.chunk.sample(self.index),
+            Resampling::Linear => {
+                let s1 = self.chunk.sample(self.index);
+                let s2 = self
+                    .chunk
+                    .next_index(self.index)
+                    .map_or(s1, |i| self.chunk.sample(i));
+                let t = self.frac as f32 / (1 << FRAC_BITS) as f32;
+                s1 + (s2 - s1) * t
+            }
+            Resampling::BandLimited => {
+                let s = self.level + self.blep[self.blep_pos];
+                self.blep[self.blep_pos] = 0.0;
+                self.blep_pos = (self.blep_pos + 1) % BLEP_LEN;
+                s
+            }
+        };
+
+        let inc = ((self.frequency as u64) << FRAC_BITS) / sample_rate as u64;
+        let pos = self.frac as u64 + inc;
+        let steps = pos >> FRAC_BITS;
+        self.frac = pos as u32 & FRAC_MASK;
+        for step in (0..steps).rev() {
+            self.index = self.chunk.next_index(self.index)?;
+            if resampling == Resampling::BandLimited {
+                // Time since the step, in output samples
+                let age = (self.frac as u64 + (step << FRAC_BITS)) as f32 / inc as f32;
+                self.add_step(self.chunk.sample(self.index), age);
+            }
+        }
+        Some(s * self.volume as f32 / 64.0)
+    }
+
+    /// Replaces the held sample, spreading the step over the next samples
+    fn add_step(&mut self, level: f32, age: f32) {
+        let delta = level - self.level;
+        self.level = level;
+        if delta == 0.0 {
+            return;
+        }
+        let offset = (age * BLEP_PHASES as f32) as usize;
+        for i in 0..BLEP_LEN {
+            let k = cmp::min(i * BLEP_PHASES + offset, BLEP_TABLE.len() - 1);
+            self.blep[(self.blep_pos + i) % BLEP_LEN] += delta * (BLEP_TABLE[k] - 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn looping_chunk() -> MixerChunk {
+        MixerChunk::new(&[100; 64], 32, 32)
+    }
+
+    /// First `len` outputs of a channel playing `data` once at half the
+    /// output rate, at a volume that leaves the samples unscaled
+    fn resample(data: &[u8], resampling: Resampling, len: usize) -> Vec<f32> {
+        let chunk = MixerChunk::new(data, data.len(), 0);
+        let mut channel = MixerChannel::new(64, chunk, 4000, SoundKind::Effect);
+        (0..len)
+            .map(|_| channel.next_sample(8000, resampling).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn nearest_holds_each_sample() {
+        let out = resample(&[0, 10, 20, 30], Resampling::Nearest, 7);
+        assert_eq!(out, vec![0.0, 0.0, 10.0, 10.0, 20.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn linear_interpolates_between_samples() {
+        let out = resample(&[0, 10, 20, 30], Resampling::Linear, 7);
+        assert_eq!(out, vec![0.0, 5.0, 10.0, 15.0, 20.0, 25.0, 30.0]);
+    }
+
+    #[test]
+    fn band_limited_steps_are_delayed_by_half_the_blep() {
+        let mut data = vec![0; 16];
+        data.extend_from_slice(&[100; 48]);
+        let out = resample(&data, Resampling::BandLimited, 127);
+        // The step to sample 16 is due at output 32, and is halfway up
+        // after the zero crossings before the center of the step
+        let delay = BLEP_ZERO_CROSSINGS;
+        assert!(out[..32].iter().all(|s| s.abs() < 0.01));
+        assert!(out[32..32 + delay].iter().all(|s| *s < 50.0));
+        assert!((out[32 + delay] - 50.0).abs() < 0.01);
+        assert!(out[32 + BLEP_LEN..]
+            .iter()
+            .all(|s| (s - 100.0).abs() < 0.01));
+    }
+
+    fn mixer_with_separation(percent: u8) -> Mixer {
+        let mut mixer = Mixer::new(Arc::new(AtomicU32::new(0)));
+        mixer.execute(MixerCommand::SetStereoSeparation(percent));
+        mixer
+    }
+
+    #[test]
+    fn mono_gains_are_equal_on_both_sides() {
+        let mixer = mixer_with_separation(0);
+        for channel in 0..NUM_CHANNELS {
+            assert_eq!(mixer.channel_gains(channel), (1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn full_separation_pans_like_paula() {
+        let mixer = mixer_with_separation(100);
+        assert_eq!(mixer.channel_gains(0), (2.0, 0.0));
+        assert_eq!(mixer.channel_gains(1), (0.0, 2.0));
+        assert_eq!(mixer.channel_gains(2), (0.0, 2.0));
+        assert_eq!(mixer.channel_gains(3), (2.0, 0.0));
+
+        // Clamped to 100
+        let mut mixer = mixer_with_separation(150);
+        mixer.play_channel(1, looping_chunk(), 8000, 0x3f, SoundKind::Effect);
+        let mut out = [0; 64];
+        mixer.mix(&mut out);
+        assert!(out.chunks(2).all(|frame| frame[0] == 0 && frame[1] > 0));
+    }
+
+    #[test]
+    fn paused_mixer_outputs_silence_and_resumes() {
+        let (mut mixer, audio) = MixerHandle::new();
+        let mut output = OfflineOutput::new(audio);
+        output.render_until(0);
+        mixer.play_channel(0, looping_chunk(), 8000, 0x3f, SoundKind::Effect);
+        assert!(output.render_until(20).iter().any(|s| *s != 0));
+
+        mixer.set_paused(true);
+        assert!(output.render_until(40).iter().all(|s| *s == 0));
+
+        mixer.set_paused(false);
+        assert!(output.render_until(60).iter().any(|s| *s != 0));
+    }
+
+    #[test]
+    fn full_queue_holds_commands_and_keeps_stops() {
+        let (mut mixer, audio) = MixerHandle::new();
+        let mut output = OfflineOutput::new(audio);
+        output.render_until(0);
+        for _ in 0..COMMAND_QUEUE_LEN + 10 {
+            mixer.play_channel(0, looping_chunk(), 8000, 0x3f, SoundKind::Effect);
+        }
+        mixer.set_paused(false);
+        mixer.stop_all();
+        // The held channel commands are dropped, the stop is not
+        assert_eq!(mixer.overflow.len(), 2);
+
+        assert!(output.render_until(20).iter().any(|s| *s != 0));
+        mixer.flush();
+        assert!(mixer.overflow.is_empty());
+        assert!(output.render_until(40).iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn stop_music_drops_held_music_commands() {
+        let (mut mixer, _audio) = MixerHandle::new();
+        for _ in 0..COMMAND_QUEUE_LEN {
+            mixer.set_stereo_separation(50);
         }
+        mixer.set_music_delay(100);
+        mixer.set_volumes(Volumes::new());
+        mixer.set_music_delay(200);
+        mixer.stop_music();
+        assert_eq!(mixer.overflow.len(), 2);
+        assert!(matches!(mixer.overflow[0], MixerCommand::SetVolumes(_)));
+        assert!(matches!(mixer.overflow[1], MixerCommand::StopMusic));
     }
 }
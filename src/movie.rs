@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Result};
+use std::path::Path;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::player::{PlayerDirection, PlayerInput};
+
+const MOVIE_MAGIC: &[u8; 4] = b"AWMV";
+/// Version 2 added music marks, version 1 movies take them from the mixer
+const MOVIE_VERSION: u16 = 2;
+
+const FLAG_BUTTON: u8 = 0b0000_0001;
+const FLAG_CODE: u8 = 0b0000_0010;
+const FLAG_PAUSE: u8 = 0b0000_0100;
+/// Followed by the music mark that was applied in the frame
+const FLAG_MARK: u8 = 0b0000_1000;
+
+/// Everything besides the input that decides how a run plays out
+#[derive(Copy, Clone, Debug)]
+pub struct MovieHeader {
+    pub game_part: u8,
+    pub seed: i16,
+    pub bypass: bool,
+}
+
+impl MovieHeader {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(MOVIE_MAGIC)?;
+        writer.write_u16::<BigEndian>(MOVIE_VERSION)?;
+        writer.write_u8(self.game_part)?;
+        writer.write_i16::<BigEndian>(self.seed)?;
+        writer.write_u8(self.bypass as u8)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<(MovieHeader, u16)> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MOVIE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a movie file"));
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version == 0 || version > MOVIE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported movie version {}", version),
+            ));
+        }
+        let header = MovieHeader {
+            game_part: reader.read_u8()?,
+            seed: reader.read_i16::<BigEndian>()?,
+            bypass: reader.read_u8()? != 0,
+        };
+        Ok((header, version))
+    }
+}
+
+pub struct MovieRecorder {
+    writer: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    pub fn create(path: &Path, header: MovieHeader) -> Result<MovieRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        header.write(&mut writer)?;
+        Ok(MovieRecorder { writer })
+    }
+
+    pub fn record(&mut self, input: &PlayerInput, music_mark: Option<i16>) -> Result<()> {
+        let mut flags = 0;
+        if input.button {
+            flags |= FLAG_BUTTON;
+        }
+        if input.code {
+            flags |= FLAG_CODE;
+        }
+        if input.pause {
+            flags |= FLAG_PAUSE;
+        }
+        if music_mark.is_some() {
+            flags |= FLAG_MARK;
+        }
+        // Only ASCII fits in the byte, the game ignores other characters
+        let last_char = if input.last_char.is_ascii() {
+            input.last_char as u8
+        } else {
+            0
+        };
+        self.writer.write_all(&[
+            input.direction.bits(),
+            flags,
+            last_char,
+        ])?;
+        match music_mark {
+            Some(mark) => self.writer.write_i16::<BigEndian>(mark),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct MoviePlayer {
+    reader: BufReader<File>,
+    pub header: MovieHeader,
+    /// Whether the music marks come from the movie rather than the mixer
+    pub has_marks: bool,
+}
+
+impl MoviePlayer {
+    pub fn open(path: &Path) -> Result<MoviePlayer> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (header, version) = MovieHeader::read(&mut reader)?;
+        Ok(MoviePlayer {
+            reader,
+            header,
+            has_marks: version >= 2,
+        })
+    }
+
+    /// Returns the input and music mark of the next frame, or `None` at the
+    /// end of the movie
+    pub fn next_frame(&mut self) -> Result<Option<(PlayerInput, Option<i16>)>> {
+        let mut frame = [0; 3];
+        match self.reader.read_exact(&mut frame) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut input = PlayerInput::new();
+        input.direction = PlayerDirection::from_bits_truncate(frame[0]);
+        input.button = frame[1] & FLAG_BUTTON != 0;
+        input.code = frame[1] & FLAG_CODE != 0;
+        input.pause = frame[1] & FLAG_PAUSE != 0;
+        input.last_char = frame[2] as char;
+        let music_mark = if frame[1] & FLAG_MARK != 0 {
+            Some(self.reader.read_i16::<BigEndian>()?)
+        } else {
+            None
+        };
+        Ok(Some((input, music_mark)))
+    }
+}
+
+pub enum Movie {
+    Recording(MovieRecorder),
+    Playing(MoviePlayer),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testutil::temp_path;
+
+    fn header() -> MovieHeader {
+        MovieHeader {
+            game_part: 3,
+            seed: -42,
+            bypass: true,
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let path = temp_path("round-trip.movie");
+        let mut input = PlayerInput::new();
+        input.direction = PlayerDirection::LEFT | PlayerDirection::UP;
+        input.button = true;
+        input.last_char = 'K';
+        let mut recorder = MovieRecorder::create(&path, header()).unwrap();
+        recorder.record(&input, None).unwrap();
+        recorder.record(&PlayerInput::new(), Some(0x1234)).unwrap();
+        drop(recorder);
+
+        let mut player = MoviePlayer::open(&path).unwrap();
+        assert_eq!(player.header.game_part, 3);
+        assert_eq!(player.header.seed, -42);
+        assert!(player.header.bypass);
+        assert!(player.has_marks);
+
+        let (first, mark) = player.next_frame().unwrap().unwrap();
+        assert_eq!(first.direction, input.direction);
+        assert!(first.button && !first.code && !first.pause);
+        assert_eq!(first.last_char, 'K');
+        assert_eq!(mark, None);
+        let (second, mark) = player.next_frame().unwrap().unwrap();
+        assert!(second.direction.is_empty() && !second.button);
+        assert_eq!(mark, Some(0x1234));
+        assert!(player.next_frame().unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn non_ascii_characters_are_not_recorded() {
+        let path = temp_path("non-ascii.movie");
+        let mut input = PlayerInput::new();
+        input.last_char = '\u{141}';
+        let mut recorder = MovieRecorder::create(&path, header()).unwrap();
+        recorder.record(&input, None).unwrap();
+        drop(recorder);
+
+        let mut player = MoviePlayer::open(&path).unwrap();
+        let (input, _) = player.next_frame().unwrap().unwrap();
+        assert_eq!(input.last_char, '\0');
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn version_1_movies_take_marks_from_the_mixer() {
+        let path = temp_path("version-1.movie");
+        let mut data = MOVIE_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 1, 2, 0, 7, 0]);
+        data.extend_from_slice(&[PlayerDirection::RIGHT.bits(), FLAG_PAUSE, 0]);
+        fs::write(&path, &data).unwrap();
+
+        let mut player = MoviePlayer::open(&path).unwrap();
+        assert!(!player.has_marks);
+        assert_eq!(player.header.seed, 7);
+        let (input, mark) = player.next_frame().unwrap().unwrap();
+        assert_eq!(input.direction, PlayerDirection::RIGHT);
+        assert!(input.pause);
+        assert_eq!(mark, None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_files_and_versions_are_rejected() {
+        let path = temp_path("rejected.movie");
+        fs::write(&path, b"AWSS\x00\x01\x01\x00\x00\x00").unwrap();
+        assert!(MoviePlayer::open(&path).is_err());
+        let mut data = MOVIE_MAGIC.to_vec();
+        data.write_u16::<BigEndian>(MOVIE_VERSION + 1).unwrap();
+        data.extend_from_slice(&[1, 0, 0, 0]);
+        fs::write(&path, &data).unwrap();
+        assert!(MoviePlayer::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -20,6 +20,15 @@ pub struct PlayerInput {
     pub save: bool,
     pub load: bool,
     pub state_slot: i8,
+    pub turbo: bool,
+    pub rewind: bool,
+    pub screenshot: bool,
+    pub replay: bool,
+    /// Switches the volume keys between master, music and effects
+    pub volume_select: bool,
+    pub volume_up: bool,
+    pub volume_down: bool,
+    pub mute: bool,
 }
 
 impl PlayerInput {
@@ -34,6 +43,20 @@ impl PlayerInput {
             save: false,
             load: false,
             state_slot: 0,
+            turbo: false,
+            rewind: false,
+            screenshot: false,
+            replay: false,
+            volume_select: false,
+            volume_up: false,
+            volume_down: false,
+            mute: false,
         }
     }
 }
+
+impl Default for PlayerInput {
+    fn default() -> PlayerInput {
+        PlayerInput::new()
+    }
+}
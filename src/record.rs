@@ -0,0 +1,271 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufWriter, Error, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::{error, info};
+
+use crate::image::{self, ImageFormat};
+use crate::mixer;
+use crate::video::Video;
+
+/// The game only waits in multiples of 20ms, so 50 fps captures every frame
+const FRAME_MS: u64 = 20;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordFormat {
+    Y4m,
+    Image(ImageFormat),
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "y4m" => Ok(RecordFormat::Y4m),
+            s => s
+                .parse()
+                .map(RecordFormat::Image)
+                .map_err(|_| format!("Unknown record format: {}", s)),
+        }
+    }
+}
+
+enum FrameWriter {
+    Y4m(BufWriter<File>),
+    Image(ImageFormat),
+}
+
+/// Captures the presented frames and the mixer output at a constant frame
+/// rate. Frames are repeated for as long as the game shows them, and the
+/// audio rendered up to the timestamp of each presented frame is written
+/// along with it, so that both streams line up when played back.
+pub struct Recorder {
+    output_dir: PathBuf,
+    frames: FrameWriter,
+    wav: hound::WavWriter<BufWriter<File>>,
+    sample_rate: u32,
+    samples_written: u64,
+    width: usize,
+    height: usize,
+    start: Option<u64>,
+    last_frame: Option<Vec<u8>>,
+    frames_written: u64,
+}
+
+impl Recorder {
+    pub fn create(
+        output_dir: PathBuf,
+        format: RecordFormat,
+        sample_rate: u32,
+        width: usize,
+        height: usize,
+    ) -> Result<Recorder> {
+        fs::create_dir_all(&output_dir)?;
+        let frames = match format {
+            RecordFormat::Y4m => {
+                let mut writer = BufWriter::new(File::create(output_dir.join("video.y4m"))?);
+                writeln!(
+                    writer,
+                    "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                    width,
+                    height,
+                    1000 / FRAME_MS
+                )?;
+                FrameWriter::Y4m(writer)
+            }
+            RecordFormat::Image(format) => FrameWriter::Image(format),
+        };
+        let spec = hound::WavSpec {
+            channels: mixer::OUTPUT_CHANNELS as u16,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let wav =
+            hound::WavWriter::create(output_dir.join("audio.wav"), spec).map_err(Error::other)?;
+        info!("Recording to {}", output_dir.to_string_lossy());
+        Ok(Recorder {
+            output_dir,
+            frames,
+            wav,
+            sample_rate,
+            samples_written: 0,
+            width,
+            height,
+            start: None,
+            last_frame: None,
+            frames_written: 0,
+        })
+    }
+
+    /// Called every time the game presents a page, at clock time `timestamp`,
+    /// with the interleaved samples mixed since the previous page
+    pub fn frame(&mut self, video: &Video, timestamp: u64, samples: &[i16]) -> Result<()> {
+        let elapsed = timestamp - *self.start.get_or_insert(timestamp);
+        self.write_samples(samples)?;
+        self.write_until(elapsed)?;
+        self.last_frame = Some(rgb_of(video));
+        Ok(())
+    }
+
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for s in samples {
+            self.wav.write_sample(*s).map_err(Error::other)?;
+        }
+        self.samples_written += (samples.len() / mixer::OUTPUT_CHANNELS as usize) as u64;
+        Ok(())
+    }
+
+    /// Writes the last frame up to `elapsed` milliseconds into the recording
+    fn write_until(&mut self, elapsed: u64) -> Result<()> {
+        if let Some(ref rgb) = self.last_frame {
+            while self.frames_written < elapsed / FRAME_MS {
+                match self.frames {
+                    FrameWriter::Y4m(ref mut writer) => {
+                        writer.write_all(b"FRAME\n")?;
+                        writer.write_all(&rgb_to_yuv444(rgb))?;
+                    }
+                    FrameWriter::Image(format) => {
+                        let file_name =
+                            format!("frame_{:06}.{}", self.frames_written, format.extension());
+                        let path = self.output_dir.join(file_name);
+                        image::write_rgb_image(&path, format, rgb, self.width, self.height)?;
+                    }
+                }
+                self.frames_written += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out the last frame, pads the audio with silence to the same
+    /// length and finalizes the files
+    pub fn finish(&mut self) -> Result<()> {
+        if self.last_frame.is_some() {
+            self.write_until((self.frames_written + 1) * FRAME_MS)?;
+            self.last_frame = None;
+        }
+        let total = self.frames_written * FRAME_MS * self.sample_rate as u64 / 1000;
+        if total > self.samples_written {
+            let padding = (total - self.samples_written) as usize;
+            self.write_samples(&vec![0; padding * mixer::OUTPUT_CHANNELS as usize])?;
+        }
+        if let FrameWriter::Y4m(ref mut writer) = self.frames {
+            writer.flush()?;
+        }
+        self.wav.flush().map_err(Error::other)?;
+        info!(
+            "Recorded {} frames and {} samples",
+            self.frames_written, self.samples_written
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Could not finish recording: {}", e);
+        }
+    }
+}
+
+fn rgb_of(video: &Video) -> Vec<u8> {
+    image::to_rgb(
+        &video.displayed_page().data,
+        video.width,
+        video.height,
+        video.palette(),
+    )
+}
+
+/// Converts packed RGB to planar BT.601 studio range Y'CbCr
+fn rgb_to_yuv444(rgb: &[u8]) -> Vec<u8> {
+    let pixels = rgb.len() / 3;
+    let mut yuv = vec![0; pixels * 3];
+    for (i, c) in rgb.chunks(3).enumerate() {
+        let (r, g, b) = (c[0] as i32, c[1] as i32, c[2] as i32);
+        yuv[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        yuv[pixels + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        yuv[2 * pixels + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+    yuv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::temp_path;
+
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 8;
+    const RATE: u32 = 1000;
+
+    /// Interleaved samples for `frames` sample frames at `RATE`
+    fn samples(frames: usize) -> Vec<i16> {
+        vec![100; frames * mixer::OUTPUT_CHANNELS as usize]
+    }
+
+    #[test]
+    fn yuv_uses_studio_range() {
+        let yuv = rgb_to_yuv444(&[0, 0, 0, 255, 255, 255, 255, 0, 0]);
+        assert_eq!(yuv, vec![16, 235, 82, 128, 128, 90, 128, 128, 240]);
+    }
+
+    #[test]
+    fn y4m_frames_follow_the_clock_and_audio_is_padded() {
+        let dir = temp_path("record-y4m");
+        let video = Video::new(WIDTH, HEIGHT);
+        let mut recorder =
+            Recorder::create(dir.clone(), RecordFormat::Y4m, RATE, WIDTH, HEIGHT).unwrap();
+        recorder.frame(&video, 1000, &[]).unwrap();
+        recorder.frame(&video, 1060, &samples(60)).unwrap();
+        recorder.frame(&video, 1070, &samples(10)).unwrap();
+        drop(recorder);
+
+        let data = fs::read(dir.join("video.y4m")).unwrap();
+        let header = b"YUV4MPEG2 W16 H8 F50:1 Ip A1:1 C444\n";
+        assert_eq!(&data[..header.len()], &header[..]);
+        let frame_size = b"FRAME\n".len() + WIDTH * HEIGHT * 3;
+        let frames = &data[header.len()..];
+        // 3 frames up to 60ms, the one shown at 70ms finishes the 4th
+        assert_eq!(frames.len(), 4 * frame_size);
+        for frame in frames.chunks(frame_size) {
+            assert_eq!(&frame[..6], b"FRAME\n");
+            let (y, chroma) = frame[6..].split_at(WIDTH * HEIGHT);
+            assert!(y.iter().all(|&v| v == 16));
+            assert!(chroma.iter().all(|&v| v == 128));
+        }
+
+        let reader = hound::WavReader::open(dir.join("audio.wav")).unwrap();
+        assert_eq!(reader.spec().channels, mixer::OUTPUT_CHANNELS as u16);
+        assert_eq!(reader.spec().sample_rate, RATE);
+        assert_eq!(reader.duration(), 80);
+        let written: Vec<i16> = reader.into_samples().map(|s| s.unwrap()).collect();
+        let (audio, padding) = written.split_at(samples(70).len());
+        assert!(audio.iter().all(|&s| s == 100));
+        assert!(padding.iter().all(|&s| s == 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_frames_are_numbered() {
+        let dir = temp_path("record-png");
+        let video = Video::new(WIDTH, HEIGHT);
+        let format = RecordFormat::Image(ImageFormat::Png);
+        let mut recorder = Recorder::create(dir.clone(), format, RATE, WIDTH, HEIGHT).unwrap();
+        recorder.frame(&video, 0, &[]).unwrap();
+        recorder.frame(&video, 40, &samples(40)).unwrap();
+        recorder.finish().unwrap();
+
+        for i in 0..3 {
+            assert!(dir.join(format!("frame_{:06}.png", i)).is_file());
+        }
+        assert!(!dir.join("frame_000003.png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
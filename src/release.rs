@@ -0,0 +1,177 @@
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+
+use crate::resource::AssetPlatform;
+
+/// A release of the game, identified by the hashes of its data files
+pub struct KnownRelease {
+    pub name: &'static str,
+    pub platform: AssetPlatform,
+    /// File names and their SHA-1 hashes, in lowercase hex
+    pub files: &'static [(&'static str, &'static str)],
+}
+
+/// Releases whose files have been checked against original disks. The
+/// `verify` subcommand of the resource tool prints the hashes of a data
+/// directory in the form used here. Entries are only added from verified
+/// originals, and releases that are not listed are reported as unknown.
+const KNOWN_RELEASES: &[KnownRelease] = &[];
+
+/// Name of the file that holds the memlist on each platform
+fn memlist_file(platform: AssetPlatform) -> &'static str {
+    match platform {
+        AssetPlatform::PC => "Memlist.bin",
+        AssetPlatform::Amiga => "another",
+        AssetPlatform::AtariST => "START.PRG",
+    }
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+/// Identifies a release by its memlist file alone, which is cheap enough
+/// to do every time the game starts
+pub fn identify_memlist(
+    asset_path: &Path,
+    platform: AssetPlatform,
+) -> Option<&'static KnownRelease> {
+    find_by_memlist(KNOWN_RELEASES, asset_path, platform)
+}
+
+fn find_by_memlist<'a>(
+    releases: &'a [KnownRelease],
+    asset_path: &Path,
+    platform: AssetPlatform,
+) -> Option<&'a KnownRelease> {
+    let name = memlist_file(platform);
+    let hash = sha1_hex(&fs::read(asset_path.join(name)).ok()?);
+    releases.iter().find(|release| {
+        release.platform == platform
+            && release
+                .files
+                .iter()
+                .any(|(n, h)| *n == name && *h == hash.as_str())
+    })
+}
+
+fn is_bank_file(name: &str) -> bool {
+    name.is_ascii()
+        && name.len() == 6
+        && name.to_lowercase().starts_with("bank")
+        && name[4..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// SHA-1 hashes of the memlist and the bank files of a data directory,
+/// sorted by file name
+pub struct Fingerprint {
+    pub files: Vec<(String, String)>,
+}
+
+impl Fingerprint {
+    /// Hashes the files in `asset_path`. This reads all game data, so it is
+    /// only done by tools and not when the game starts.
+    pub fn compute(asset_path: &Path, platform: AssetPlatform) -> Result<Fingerprint> {
+        let mut names = vec![memlist_file(platform).to_string()];
+        for entry in fs::read_dir(asset_path)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if is_bank_file(&name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let mut files = Vec::new();
+        for name in names {
+            let data = fs::read(asset_path.join(&name))?;
+            let hash = sha1_hex(&data);
+            files.push((name, hash));
+        }
+        Ok(Fingerprint { files })
+    }
+
+    /// Looks for the known release whose files are all present with the
+    /// expected hashes
+    pub fn identify(&self, platform: AssetPlatform) -> Option<&'static KnownRelease> {
+        self.find_in(KNOWN_RELEASES, platform)
+    }
+
+    fn find_in<'a>(
+        &self,
+        releases: &'a [KnownRelease],
+        platform: AssetPlatform,
+    ) -> Option<&'a KnownRelease> {
+        releases.iter().find(|release| {
+            release.platform == platform
+                && release.files.iter().all(|(name, hash)| {
+                    self.files
+                        .iter()
+                        .any(|(n, h)| n.as_str() == *name && h.as_str() == *hash)
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::temp_path;
+
+    const RELEASES: &[KnownRelease] = &[KnownRelease {
+        name: "Test release",
+        platform: AssetPlatform::PC,
+        files: &[
+            // SHA-1 of "memlist" and "bank"
+            ("Memlist.bin", "8749495cbaf8543e07fe7c9e6e040810eab51758"),
+            ("Bank01", "bdd240c8fe7174e6ac1cfdd5282de76eb7ad6815"),
+        ],
+    }];
+
+    #[test]
+    fn release_is_identified_by_its_hashes() {
+        let dir = temp_path("release");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Memlist.bin"), b"memlist").unwrap();
+        fs::write(dir.join("Bank01"), b"bank").unwrap();
+        fs::write(dir.join("README"), b"not game data").unwrap();
+        let fingerprint = Fingerprint::compute(&dir, AssetPlatform::PC).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = fingerprint.files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["Bank01", "Memlist.bin"]);
+        let release = fingerprint.find_in(RELEASES, AssetPlatform::PC);
+        assert_eq!(release.map(|r| r.name), Some("Test release"));
+        assert!(fingerprint
+            .find_in(RELEASES, AssetPlatform::Amiga)
+            .is_none());
+    }
+
+    #[test]
+    fn release_is_identified_by_its_memlist() {
+        let dir = temp_path("release-memlist");
+        fs::create_dir_all(&dir).unwrap();
+        assert!(find_by_memlist(RELEASES, &dir, AssetPlatform::PC).is_none());
+        fs::write(dir.join("Memlist.bin"), b"memlist").unwrap();
+        let release = find_by_memlist(RELEASES, &dir, AssetPlatform::PC);
+        assert_eq!(release.map(|r| r.name), Some("Test release"));
+        fs::write(dir.join("Memlist.bin"), b"changed").unwrap();
+        assert!(find_by_memlist(RELEASES, &dir, AssetPlatform::PC).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_are_not_identified() {
+        let mut fingerprint = Fingerprint {
+            files: RELEASES[0]
+                .files
+                .iter()
+                .map(|(n, h)| (n.to_string(), h.to_string()))
+                .collect(),
+        };
+        assert!(fingerprint.find_in(RELEASES, AssetPlatform::PC).is_some());
+        fingerprint.files[1].1 = "0".repeat(40);
+        assert!(fingerprint.find_in(RELEASES, AssetPlatform::PC).is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Error, Result};
+use std::path::Path;
+
+use crate::video::{Palette, Video};
+
+/// Hard limit on the number of frames, as hires pages are 256K each
+const MAX_FRAMES: usize = 500;
+
+struct ReplayFrame {
+    data: Vec<u8>,
+    palette: Palette,
+    /// How long the frame stays on screen, in milliseconds
+    duration: u64,
+}
+
+/// The last few seconds of presented frames, for exporting as a GIF
+pub struct ReplayBuffer {
+    frames: VecDeque<ReplayFrame>,
+    total: u64,
+    duration: u64,
+}
+
+impl ReplayBuffer {
+    /// Creates a buffer holding `duration` milliseconds of frames
+    pub fn new(duration: u64) -> ReplayBuffer {
+        ReplayBuffer {
+            frames: VecDeque::new(),
+            total: 0,
+            duration,
+        }
+    }
+
+    /// Adds the page on screen. `delay` is the time the game waited before
+    /// presenting it, which is how long the previous frame was shown.
+    pub fn push(&mut self, video: &Video, delay: u64) {
+        if let Some(last) = self.frames.back_mut() {
+            self.total = self.total - last.duration + delay;
+            last.duration = delay;
+        }
+        let data = video
+            .displayed_page()
+            .data
+            .iter()
+            .map(|i| i & 0x0f)
+            .collect();
+        self.frames.push_back(ReplayFrame {
+            data,
+            palette: *video.palette(),
+            duration: delay,
+        });
+        self.total += delay;
+
+        while self.frames.len() > MAX_FRAMES
+            || (self.frames.len() > 1 && self.total - self.frames[0].duration >= self.duration)
+        {
+            if let Some(oldest) = self.frames.pop_front() {
+                self.total -= oldest.duration;
+            }
+        }
+    }
+
+    /// How long each buffered frame is shown, oldest first
+    #[cfg(test)]
+    pub(crate) fn durations(&self) -> Vec<u64> {
+        self.frames.iter().map(|f| f.duration).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.total = 0;
+    }
+
+    pub fn write_gif(&self, path: &Path, width: usize, height: usize) -> Result<()> {
+        let first = match self.frames.front() {
+            Some(frame) => frame,
+            None => return Err(Error::other("No frames to write")),
+        };
+        let global_palette = palette_rgb(&first.palette);
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &global_palette)
+            .map_err(Error::other)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(Error::other)?;
+
+        for frame in self.frames.iter() {
+            let palette = palette_rgb(&frame.palette);
+            let gif_frame = gif::Frame {
+                width: width as u16,
+                height: height as u16,
+                // GIF delays are in hundredths of a second
+                delay: (frame.duration / 10) as u16,
+                palette: if palette != global_palette {
+                    Some(palette)
+                } else {
+                    None
+                },
+                buffer: Cow::Borrowed(&frame.data[..width * height]),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&gif_frame).map_err(Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+fn palette_rgb(palette: &Palette) -> Vec<u8> {
+    palette
+        .entries
+        .iter()
+        .flat_map(|c| vec![c.r, c.g, c.b])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testutil::temp_path;
+
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 8;
+
+    #[test]
+    fn each_delay_sets_the_previous_frame_duration() {
+        let video = Video::new(WIDTH, HEIGHT);
+        let mut buffer = ReplayBuffer::new(1000);
+        buffer.push(&video, 20);
+        buffer.push(&video, 60);
+        buffer.push(&video, 100);
+        assert_eq!(buffer.durations(), vec![60, 100, 100]);
+        assert_eq!(buffer.total, 260);
+    }
+
+    #[test]
+    fn push_evicts_frames_beyond_the_duration() {
+        let video = Video::new(WIDTH, HEIGHT);
+        let mut buffer = ReplayBuffer::new(100);
+        for _ in 0..10 {
+            buffer.push(&video, 40);
+        }
+        // The oldest frame goes once the others fill the window on their own
+        assert_eq!(buffer.durations(), vec![40, 40, 40]);
+        assert_eq!(buffer.total, 120);
+
+        buffer.clear();
+        assert!(buffer.durations().is_empty());
+        assert_eq!(buffer.total, 0);
+    }
+
+    #[test]
+    fn push_keeps_at_most_max_frames() {
+        let video = Video::new(WIDTH, HEIGHT);
+        let mut buffer = ReplayBuffer::new(u64::MAX);
+        for _ in 0..MAX_FRAMES + 10 {
+            buffer.push(&video, 20);
+        }
+        assert_eq!(buffer.frames.len(), MAX_FRAMES);
+        assert_eq!(buffer.total, MAX_FRAMES as u64 * 20);
+    }
+
+    #[test]
+    fn gif_delays_are_in_hundredths() {
+        let path = temp_path("replay.gif");
+        let video = Video::new(WIDTH, HEIGHT);
+        let mut buffer = ReplayBuffer::new(1000);
+        assert!(buffer.write_gif(&path, WIDTH, HEIGHT).is_err());
+        buffer.push(&video, 40);
+        buffer.push(&video, 80);
+        buffer.write_gif(&path, WIDTH, HEIGHT).unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!((frame.width, frame.height), (WIDTH as u16, HEIGHT as u16));
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, vec![8, 8]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,12 +3,15 @@ use std::io::prelude::*;
 use std::io::{Cursor, Error, ErrorKind, Result, SeekFrom};
 use std::path::{Path, PathBuf};
 
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use log::{debug, info, warn};
 
 use crate::bank::Bank;
+use crate::error;
+use crate::manifest::ManifestEntry;
 use crate::mixer::MixerChunk;
 use crate::parts;
+use crate::release;
 use crate::sfxplayer::{SfxInstrument, SfxModule};
 
 const MEM_BLOCK_SIZE: usize = 600 * 1024;
@@ -20,6 +23,16 @@ pub enum AssetPlatform {
     AtariST,
 }
 
+impl AssetPlatform {
+    pub fn name(self) -> &'static str {
+        match self {
+            AssetPlatform::PC => "pc",
+            AssetPlatform::Amiga => "amiga",
+            AssetPlatform::AtariST => "atari_st",
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum MemEntryState {
     NotNeeded = 0,
@@ -66,6 +79,47 @@ impl EntryType {
             n => EntryType::Unknown(n),
         }
     }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            EntryType::Sound => 0,
+            EntryType::Music => 1,
+            EntryType::PolyAnim => 2,
+            EntryType::Palette => 3,
+            EntryType::Bytecode => 4,
+            EntryType::PolyCinematic => 5,
+            EntryType::Unknown(n) => n,
+        }
+    }
+
+    /// Parses a name as returned by `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sound" => Some(EntryType::Sound),
+            "music" => Some(EntryType::Music),
+            "poly_anim" => Some(EntryType::PolyAnim),
+            "palette" => Some(EntryType::Palette),
+            "bytecode" => Some(EntryType::Bytecode),
+            "poly_cinematic" => Some(EntryType::PolyCinematic),
+            name => name
+                .strip_prefix("unknown_")
+                .and_then(|n| n.parse().ok())
+                .map(EntryType::Unknown),
+        }
+    }
+
+    /// Name used for extracted files and in the manifest
+    pub fn name(self) -> String {
+        match self {
+            EntryType::Sound => "sound".to_string(),
+            EntryType::Music => "music".to_string(),
+            EntryType::PolyAnim => "poly_anim".to_string(),
+            EntryType::Palette => "palette".to_string(),
+            EntryType::Bytecode => "bytecode".to_string(),
+            EntryType::PolyCinematic => "poly_cinematic".to_string(),
+            EntryType::Unknown(n) => format!("unknown_{}", n),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -83,6 +137,84 @@ pub struct MemEntry {
     size: usize,
 }
 
+impl MemEntry {
+    /// Describes the entry for the manifest of extracted resources
+    pub fn manifest_entry(&self, index: usize, file: Option<String>) -> ManifestEntry {
+        ManifestEntry {
+            index,
+            file,
+            state: self.state as u8,
+            entry_type: self.entry_type.name(),
+            buf_ptr: self.buf_ptr as u16,
+            rank_num: self.rank_num,
+            bank_id: self.bank_id,
+            bank_offset: self.bank_offset,
+            packed_size: self.packed_size as u16,
+            size: self.size as u16,
+            unk4: self.unk4,
+            unkc: self.unkc,
+            unk10: self.unk10,
+        }
+    }
+
+    /// Builds an entry back from its manifest description
+    pub fn from_manifest_entry(entry: &ManifestEntry) -> Result<MemEntry> {
+        let state = match MemEntryState::from_u8(entry.state)? {
+            MemEntryState::EndOfMemList => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Entry {} marks the end of the memlist", entry.index),
+                ))
+            }
+            state => state,
+        };
+        let entry_type = EntryType::from_name(&entry.entry_type).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown entry type: {}", entry.entry_type),
+            )
+        })?;
+        Ok(MemEntry {
+            state,
+            entry_type,
+            buf_ptr: entry.buf_ptr as usize,
+            unk4: entry.unk4,
+            rank_num: entry.rank_num,
+            bank_id: entry.bank_id,
+            bank_offset: entry.bank_offset,
+            unkc: entry.unkc,
+            packed_size: entry.packed_size as usize,
+            unk10: entry.unk10,
+            size: entry.size as usize,
+        })
+    }
+
+    /// Writes the entry in the same format `MemlistReader` reads
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u8(self.state as u8)?;
+        writer.write_u8(self.entry_type.to_u8())?;
+        writer.write_u16::<BigEndian>(self.buf_ptr as u16)?;
+        writer.write_u16::<BigEndian>(self.unk4)?;
+        writer.write_u8(self.rank_num)?;
+        writer.write_u8(self.bank_id)?;
+        writer.write_u32::<BigEndian>(self.bank_offset)?;
+        writer.write_u16::<BigEndian>(self.unkc)?;
+        writer.write_u16::<BigEndian>(self.packed_size as u16)?;
+        writer.write_u16::<BigEndian>(self.unk10)?;
+        writer.write_u16::<BigEndian>(self.size as u16)?;
+        Ok(())
+    }
+}
+
+/// Writes a PC style `Memlist.bin`, terminated by an end of list entry
+pub fn write_memlist<W: Write>(mem_list: &[MemEntry], writer: &mut W) -> Result<()> {
+    for entry in mem_list {
+        entry.write(writer)?;
+    }
+    writer.write_u8(MemEntryState::EndOfMemList as u8)?;
+    writer.write_all(&[0; 19])
+}
+
 pub struct MemlistReader {
     asset_path: PathBuf,
     asset_platform: AssetPlatform,
@@ -107,6 +239,10 @@ impl MemlistReader {
             info!("Assuming PC / Memlist.bin version");
             AssetPlatform::PC
         };
+        match release::identify_memlist(&asset_path, asset_platform) {
+            Some(release) => info!("Identified {}", release.name),
+            None => info!("Not a known release"),
+        }
         MemlistReader::new(asset_path, asset_platform)
     }
 
@@ -219,14 +355,14 @@ impl Resource {
         }
     }
 
-    pub fn setup_part(&mut self, part_id: u16) {
+    pub fn setup_part(&mut self, part_id: u16) -> error::Result<()> {
         debug!("setup_part: {}", part_id);
         if part_id == self.current_part_id {
-            return;
+            return Ok(());
         }
 
         if part_id < parts::GAME_PART_FIRST || part_id > parts::GAME_PART_LAST {
-            panic!("Unknown part: {:x}", part_id);
+            return Err(error::Error::UnknownPart(part_id));
         }
 
         let index = (part_id - parts::GAME_PART_FIRST) as usize;
@@ -248,7 +384,7 @@ impl Resource {
             self.mem_list[video2_index].state = MemEntryState::LoadMe;
         }
 
-        self.load_marked_as_needed();
+        self.load_marked_as_needed()?;
 
         self.seg_palettes = self.mem_list[palette_index].buf_ptr;
         self.seg_bytecode = self.mem_list[code_index].buf_ptr;
@@ -265,6 +401,63 @@ impl Resource {
         self.current_part_id = part_id;
 
         self.script_bak_ptr = self.script_cur_ptr;
+        Ok(())
+    }
+
+    pub fn save_state<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.current_part_id)?;
+        for ptr in &[
+            self.script_bak_ptr,
+            self.script_cur_ptr,
+            self.vid_bak_ptr,
+            self.vid_cur_ptr,
+            self.seg_palettes,
+            self.seg_bytecode,
+            self.seg_cinematic,
+            self.seg_video2,
+        ] {
+            writer.write_u32::<BigEndian>(*ptr as u32)?;
+        }
+        writer.write_u8(self.copy_vid_ptr as u8)?;
+        writer.write_u16::<BigEndian>(self.mem_list.len() as u16)?;
+        for entry in self.mem_list.iter() {
+            writer.write_u8(entry.state as u8)?;
+            writer.write_u32::<BigEndian>(entry.buf_ptr as u32)?;
+        }
+        writer.write_all(&self.memory)
+    }
+
+    pub fn load_state<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        self.current_part_id = reader.read_u16::<BigEndian>()?;
+        let mut ptrs = [0; 8];
+        for ptr in ptrs.iter_mut() {
+            *ptr = reader.read_u32::<BigEndian>()? as usize;
+        }
+        self.script_bak_ptr = ptrs[0];
+        self.script_cur_ptr = ptrs[1];
+        self.vid_bak_ptr = ptrs[2];
+        self.vid_cur_ptr = ptrs[3];
+        self.seg_palettes = ptrs[4];
+        self.seg_bytecode = ptrs[5];
+        self.seg_cinematic = ptrs[6];
+        self.seg_video2 = ptrs[7];
+        self.copy_vid_ptr = reader.read_u8()? != 0;
+        let num_entries = reader.read_u16::<BigEndian>()? as usize;
+        if num_entries != self.mem_list.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "State has {} memlist entries, expected {}",
+                    num_entries,
+                    self.mem_list.len()
+                ),
+            ));
+        }
+        for entry in self.mem_list.iter_mut() {
+            entry.state = MemEntryState::from_u8(reader.read_u8()?)?;
+            entry.buf_ptr = reader.read_u32::<BigEndian>()? as usize;
+        }
+        reader.read_exact(&mut self.memory)
     }
 
     pub fn read_byte(&mut self, index: usize) -> u8 {
@@ -282,13 +475,14 @@ impl Resource {
         self.script_cur_ptr = self.script_bak_ptr;
     }
 
-    pub fn load_memory_entry(&mut self, resource_id: u16) {
+    pub fn load_memory_entry(&mut self, resource_id: u16) -> error::Result<()> {
         let resource_id = resource_id as usize;
         let entry = &mut self.mem_list[resource_id];
         if entry.state == MemEntryState::NotNeeded {
             entry.state = MemEntryState::LoadMe;
-            self.load_marked_as_needed();
+            self.load_marked_as_needed()?;
         }
+        Ok(())
     }
 
     pub fn video_page_data(&self) -> Vec<u8> {
@@ -359,7 +553,7 @@ impl Resource {
         resource_id: u16,
         delay: &mut u16,
         pos: u8,
-    ) -> Result<Option<SfxModule>> {
+    ) -> error::Result<Option<SfxModule>> {
         debug!("load_sfx_module(0x{:x}, {}, {}", resource_id, delay, pos);
         let resource_id = resource_id as usize;
         let entry = &self.mem_list[resource_id];
@@ -392,7 +586,7 @@ impl Resource {
         Ok(Some(module))
     }
 
-    fn prepare_instrument(&self, buf: &[u8]) -> Result<Option<SfxInstrument>> {
+    fn prepare_instrument(&self, buf: &[u8]) -> error::Result<Option<SfxInstrument>> {
         let mut buffer = Cursor::new(&buf);
         let resource_id = buffer.read_u16::<BigEndian>()?;
         if resource_id == 0 {
@@ -401,7 +595,7 @@ impl Resource {
         let volume = buffer.read_u16::<BigEndian>()?;
         let entry = &self.mem_list[resource_id as usize];
         if entry.state != MemEntryState::Loaded || entry.entry_type != EntryType::Sound {
-            panic!("Error loading instrument 0x{:x}", resource_id);
+            return Err(error::Error::BadInstrument(resource_id));
         }
         let mut data = self.memory[entry.buf_ptr..entry.buf_ptr + entry.size].to_vec();
         if data.len() == 0 {
@@ -413,22 +607,68 @@ impl Resource {
         Ok(Some(SfxInstrument::new(data, volume)))
     }
 
+    /// Reads and unpacks the data of an entry without loading it. Entries
+    /// that are in no bank have no data.
+    pub fn read_entry_data(&self, resource_id: u16) -> error::Result<Option<Vec<u8>>> {
+        let entry = &self.mem_list[resource_id as usize];
+        if entry.bank_id == 0 {
+            return Ok(None);
+        }
+        Resource::read_entry(&self.asset_path, entry, &self.asset_platform, resource_id).map(Some)
+    }
+
+    /// Unpacks every resource and returns the problems found, by resource id
+    pub fn verify(&self) -> Vec<(u16, error::Error)> {
+        (0..self.mem_list.len() as u16)
+            .filter_map(|resource_id| match self.read_entry_data(resource_id) {
+                Ok(_) => None,
+                Err(e) => Some((resource_id, e)),
+            })
+            .collect()
+    }
+
+    fn read_entry(
+        asset_path: &Path,
+        mem_entry: &MemEntry,
+        asset_platform: &AssetPlatform,
+        resource_id: u16,
+    ) -> error::Result<Vec<u8>> {
+        let bank = Resource::read_bank(asset_path, mem_entry, asset_platform)?;
+        debug!("read_bank() rank_num: {} packed_size: 0x{:x} size: 0x{:x} type={:?} pos={:x} bank_id={:x}", mem_entry.rank_num, mem_entry.packed_size, mem_entry.size, mem_entry.entry_type, mem_entry.bank_offset, mem_entry.bank_id);
+
+        let data = bank.data()?;
+        if data.len() != mem_entry.size {
+            return Err(error::Error::SizeMismatch {
+                resource_id,
+                expected: mem_entry.size,
+                actual: data.len(),
+            });
+        }
+        Ok(data)
+    }
+
     fn read_bank(
         asset_path: &Path,
         mem_entry: &MemEntry,
         asset_platform: &AssetPlatform,
-    ) -> std::io::Result<Bank> {
+    ) -> error::Result<Bank> {
         let file_name = match asset_platform {
             AssetPlatform::PC => asset_path.join(format!("Bank{:02x}", mem_entry.bank_id)),
             AssetPlatform::Amiga => asset_path.join(format!("bank{:02X}", mem_entry.bank_id)),
             AssetPlatform::AtariST => asset_path.join(format!("BANK{:02X}", mem_entry.bank_id)),
         };
         debug!("Reading bank: {}", file_name.to_string_lossy());
-        let mut file = File::open(file_name)?;
-        file.seek(SeekFrom::Start(mem_entry.bank_offset as u64))?;
-
-        let mut data = vec![0; mem_entry.packed_size as usize];
-        file.read_exact(&mut data)?;
+        let read = || -> Result<Vec<u8>> {
+            let mut file = File::open(&file_name)?;
+            file.seek(SeekFrom::Start(mem_entry.bank_offset as u64))?;
+            let mut data = vec![0; mem_entry.packed_size as usize];
+            file.read_exact(&mut data)?;
+            Ok(data)
+        };
+        let data = read().map_err(|source| error::Error::MissingBank {
+            path: file_name.clone(),
+            source,
+        })?;
         let bank = if mem_entry.packed_size == mem_entry.size {
             Bank::Uncompressed(data)
         } else {
@@ -445,21 +685,24 @@ impl Resource {
         self.script_cur_ptr = 0;
     }
 
-    fn load_marked_as_needed(&mut self) {
-        let to_load: Vec<&mut MemEntry> = self
+    fn load_marked_as_needed(&mut self) -> error::Result<()> {
+        let to_load: Vec<(usize, &mut MemEntry)> = self
             .mem_list
             .iter_mut()
-            .filter(|e| e.state == MemEntryState::LoadMe)
+            .enumerate()
+            .filter(|(_, e)| e.state == MemEntryState::LoadMe)
             .collect();
 
-        for entry in to_load {
+        for (resource_id, entry) in to_load {
             let load_destination = match entry.entry_type {
                 EntryType::PolyAnim => self.vid_cur_ptr,
                 _ => {
                     if entry.size > self.vid_bak_ptr - self.script_cur_ptr {
-                        warn!("Resource: Not enough memory to load resource");
                         entry.state = MemEntryState::NotNeeded;
-                        continue;
+                        return Err(error::Error::OutOfMemory {
+                            resource_id: resource_id as u16,
+                            size: entry.size,
+                        });
                     }
                     self.script_cur_ptr
                 }
@@ -472,14 +715,14 @@ impl Resource {
                 continue;
             }
 
-            let bank = Resource::read_bank(&self.asset_path, &entry, &self.asset_platform)
-                .expect("Could not read bank");
-            debug!("read_bank() rank_num: {} packed_size: 0x{:x} size: 0x{:x} type={:?} pos={:x} bank_id={:x}", entry.rank_num, entry.packed_size, entry.size, entry.entry_type, entry.bank_offset, entry.bank_id);
-
+            let data = Resource::read_entry(
+                &self.asset_path,
+                entry,
+                &self.asset_platform,
+                resource_id as u16,
+            )?;
             let load_destination_end = load_destination + entry.size;
             let dst = &mut self.memory[load_destination..load_destination_end];
-            let data = bank.data();
-            assert!(data.len() == entry.size);
             dst.copy_from_slice(&data);
             if let EntryType::PolyAnim = entry.entry_type {
                 self.copy_vid_ptr = true;
@@ -490,5 +733,41 @@ impl Resource {
                 self.script_cur_ptr += entry.size;
             }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: usize) -> MemEntry {
+        MemEntry {
+            state: MemEntryState::NotNeeded,
+            entry_type: EntryType::Sound,
+            buf_ptr: 0,
+            unk4: 0,
+            rank_num: 0,
+            bank_id: 1,
+            bank_offset: 0,
+            unkc: 0,
+            packed_size: size,
+            unk10: 0,
+            size,
+        }
+    }
+
+    #[test]
+    fn resources_that_do_not_fit_are_out_of_memory() {
+        let mem_list = vec![entry(MEM_BLOCK_SIZE)];
+        let mut resource = Resource::new(mem_list, PathBuf::new(), AssetPlatform::PC);
+        match resource.load_memory_entry(0) {
+            Err(error::Error::OutOfMemory { resource_id, size }) => {
+                assert_eq!((resource_id, size), (0, MEM_BLOCK_SIZE));
+            }
+            r => panic!("Expected OutOfMemory, got {:?}", r),
+        }
+        assert_eq!(resource.mem_list[0].state, MemEntryState::NotNeeded);
+        assert_eq!(resource.script_cur_ptr, 0);
     }
 }
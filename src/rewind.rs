@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::{debug, warn};
+
+/// Upper bound for the memory used by the deltas, regardless of duration
+const MAX_DELTA_BYTES: usize = 64 * 1024 * 1024;
+
+const MIN_ZERO_RUN: usize = 8;
+const MAX_RUN: usize = 0xffff;
+
+struct Delta {
+    timestamp: u64,
+    data: Vec<u8>,
+}
+
+/// Ring of VM snapshots for stepping back in time.
+///
+/// Only the most recent snapshot is kept in full. Older ones are stored as
+/// run length encoded XOR deltas against the next newer snapshot, so that
+/// walking backwards is a matter of applying deltas one after the other.
+pub struct RewindBuffer {
+    current: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+    delta_bytes: usize,
+    duration: u64,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer holding `duration` milliseconds of game time
+    pub fn new(duration: u64) -> RewindBuffer {
+        RewindBuffer {
+            current: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            duration,
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>, timestamp: u64) {
+        if let Some(current) = self.current.take() {
+            if current.len() == state.len() {
+                let data = encode_delta(&current, &state);
+                self.delta_bytes += data.len();
+                self.deltas.push_back(Delta { timestamp, data });
+            } else {
+                warn!("Snapshot size changed, clearing rewind buffer");
+                self.clear();
+            }
+        }
+        self.current = Some(state);
+
+        while let Some(oldest) = self.deltas.front() {
+            if timestamp.saturating_sub(oldest.timestamp) <= self.duration
+                && self.delta_bytes <= MAX_DELTA_BYTES
+            {
+                break;
+            }
+            self.delta_bytes -= oldest.data.len();
+            self.deltas.pop_front();
+        }
+        debug!(
+            "Rewind buffer: {} deltas, {} bytes",
+            self.deltas.len(),
+            self.delta_bytes
+        );
+    }
+
+    /// Moves one snapshot back and returns it. Stays on the oldest snapshot
+    /// once the buffer is exhausted.
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        let current = self.current.as_mut()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.data.len();
+            apply_delta(current, &delta.data);
+        }
+        Some(current)
+    }
+
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+}
+
+/// Encodes `older ^ newer` as a list of (zero run, literal run, literals)
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = older.iter().zip(newer.iter()).map(|(a, b)| a ^ b).collect();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < xor.len() {
+        let start = i;
+        while i < xor.len() && xor[i] == 0 && i - start < MAX_RUN {
+            i += 1;
+        }
+        let zeros = i - start;
+
+        let literal_start = i;
+        let mut zero_count = 0;
+        while i < xor.len() && i - literal_start < MAX_RUN {
+            if xor[i] == 0 {
+                zero_count += 1;
+                if zero_count == MIN_ZERO_RUN {
+                    i -= MIN_ZERO_RUN - 1;
+                    break;
+                }
+            } else {
+                zero_count = 0;
+            }
+            i += 1;
+        }
+        // Trailing zeros are cheaper as the next zero run
+        let mut literal_end = i;
+        while literal_end > literal_start && xor[literal_end - 1] == 0 {
+            literal_end -= 1;
+        }
+        i = literal_end;
+
+        let mut header = [0; 4];
+        BigEndian::write_u16(&mut header, zeros as u16);
+        BigEndian::write_u16(&mut header[2..], (literal_end - literal_start) as u16);
+        result.extend_from_slice(&header);
+        result.extend_from_slice(&xor[literal_start..literal_end]);
+    }
+    result
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+    while i + 4 <= delta.len() {
+        pos += BigEndian::read_u16(&delta[i..]) as usize;
+        let len = BigEndian::read_u16(&delta[i + 2..]) as usize;
+        i += 4;
+        for (s, d) in state[pos..pos + len].iter_mut().zip(&delta[i..i + len]) {
+            *s ^= d;
+        }
+        pos += len;
+        i += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(older: &[u8], newer: &[u8]) -> Vec<u8> {
+        let delta = encode_delta(older, newer);
+        let mut state = newer.to_vec();
+        apply_delta(&mut state, &delta);
+        assert!(state == older);
+        delta
+    }
+
+    #[test]
+    fn equal_snapshots_give_small_deltas() {
+        let state = pattern(200_000, 1);
+        assert!(round_trip(&state, &state).len() < 32);
+    }
+
+    #[test]
+    fn sparse_changes_round_trip() {
+        let older = pattern(200_000, 2);
+        let mut newer = older.clone();
+        for i in (0..newer.len()).step_by(997) {
+            newer[i] ^= 0x5a;
+        }
+        let last = newer.len() - 1;
+        newer[last] ^= 1;
+        assert!(round_trip(&older, &newer).len() < 2000);
+    }
+
+    #[test]
+    fn long_runs_round_trip() {
+        let older = pattern(3 * MAX_RUN, 3);
+        let mut newer = older.clone();
+        for b in newer[MAX_RUN / 2..2 * MAX_RUN + 10].iter_mut() {
+            *b = !*b;
+        }
+        round_trip(&older, &newer);
+        round_trip(&older, &pattern(3 * MAX_RUN, 4));
+        round_trip(&[], &[]);
+    }
+
+    #[test]
+    fn step_back_walks_to_the_oldest_snapshot() {
+        let states: Vec<Vec<u8>> = (0..4).map(|i| pattern(1000, i)).collect();
+        let mut buffer = RewindBuffer::new(1000);
+        for (i, state) in states.iter().enumerate() {
+            buffer.push(state.clone(), i as u64 * 100);
+        }
+        for state in states[..3].iter().rev() {
+            assert!(buffer.step_back().unwrap() == &state[..]);
+        }
+        assert!(buffer.step_back().unwrap() == &states[0][..]);
+    }
+
+    #[test]
+    fn snapshots_older_than_the_duration_are_dropped() {
+        let mut buffer = RewindBuffer::new(150);
+        for i in 0..5 {
+            buffer.push(pattern(100, i), i as u64 * 100);
+        }
+        assert!(buffer.step_back().unwrap() == &pattern(100, 3)[..]);
+        assert!(buffer.step_back().unwrap() == &pattern(100, 2)[..]);
+        assert!(buffer.step_back().unwrap() == &pattern(100, 2)[..]);
+    }
+}
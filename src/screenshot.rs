@@ -0,0 +1,176 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufWriter, Result};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+
+use crate::image;
+use crate::replay::ReplayBuffer;
+use crate::video::Video;
+
+pub struct ScreenshotOptions {
+    pub dir: PathBuf,
+    /// Also write all four pages and the palette next to the screenshot
+    pub bundle: bool,
+}
+
+impl ScreenshotOptions {
+    pub fn new() -> ScreenshotOptions {
+        let dir = dirs::picture_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("anotherworld");
+        ScreenshotOptions { dir, bundle: false }
+    }
+}
+
+impl Default for ScreenshotOptions {
+    fn default() -> ScreenshotOptions {
+        ScreenshotOptions::new()
+    }
+}
+
+/// Writes the page currently on screen as a PNG with the active palette
+pub fn save_screenshot(video: &Video, options: &ScreenshotOptions) -> Result<PathBuf> {
+    fs::create_dir_all(&options.dir)?;
+    let name = timestamped_name();
+    let path = options.dir.join(format!("{}.png", name));
+    image::write_png(
+        &path,
+        &video.displayed_page().data,
+        video.width,
+        video.height,
+        video.palette(),
+    )?;
+
+    if options.bundle {
+        save_bundle(video, &options.dir.join(name))?;
+    }
+    Ok(path)
+}
+
+/// Writes the replay buffer as an animated GIF
+pub fn save_replay(
+    replay: &ReplayBuffer,
+    video: &Video,
+    options: &ScreenshotOptions,
+) -> Result<PathBuf> {
+    fs::create_dir_all(&options.dir)?;
+    let path = options.dir.join(format!("{}.gif", timestamped_name()));
+    replay.write_gif(&path, video.width, video.height)?;
+    Ok(path)
+}
+
+fn timestamped_name() -> String {
+    format!("anotherworld-{}", Local::now().format("%Y%m%d-%H%M%S%.3f"))
+}
+
+fn save_bundle(video: &Video, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    for (i, page) in video.pages().iter().enumerate() {
+        image::write_png(
+            &dir.join(format!("page{}.png", i)),
+            &page.data,
+            video.width,
+            video.height,
+            video.palette(),
+        )?;
+    }
+
+    let mut info = BufWriter::new(File::create(dir.join("palette.txt"))?);
+    let (ptr1, ptr2, ptr3) = video.page_ptrs();
+    writeln!(
+        info,
+        "# draw page {}, displayed page {}, back page {}",
+        ptr1, ptr2, ptr3
+    )?;
+    for (i, c) in video.palette().entries.iter().enumerate() {
+        writeln!(info, "{:2} #{:02x}{:02x}{:02x}", i, c.r, c.g, c.b)?;
+    }
+    info.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    use crate::testutil::{temp_path, StubSys};
+
+    /// Video showing page 1, with a distinct color for every palette index
+    /// and a different pattern on each page
+    fn video(width: usize, height: usize) -> Video {
+        let mut state = Vec::new();
+        state.write_u16::<BigEndian>(width as u16).unwrap();
+        state.write_u16::<BigEndian>(height as u16).unwrap();
+        state.extend_from_slice(&[0, 1, 2]);
+        for i in 0..16u8 {
+            state.extend_from_slice(&[i * 16, 255 - i * 16, i]);
+        }
+        for page in 0..4 {
+            state.extend((0..width * height).map(|i| ((i / 7 + page) % 16) as u8));
+        }
+        let mut video = Video::new(width, height);
+        video.load_state(&mut Cursor::new(state)).unwrap();
+        video.update_display(&mut StubSys::new(Vec::new()), 0xfe);
+        video
+    }
+
+    /// Decodes a PNG to its size and RGB pixels
+    fn read_png(path: &Path) -> (u32, u32, Vec<u8>) {
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut rgb = vec![0; info.buffer_size()];
+        reader.next_frame(&mut rgb).unwrap();
+        (info.width, info.height, rgb)
+    }
+
+    fn check_screenshot(width: usize, height: usize) {
+        let video = video(width, height);
+        let options = ScreenshotOptions {
+            dir: temp_path(&format!("screenshot-{}", width)),
+            bundle: false,
+        };
+        let path = save_screenshot(&video, &options).unwrap();
+        let (png_width, png_height, rgb) = read_png(&path);
+        assert_eq!((png_width as usize, png_height as usize), (width, height));
+        let page = &video.pages()[1].data;
+        assert_eq!(&rgb[..3], &[16, 239, 1]);
+        let last = page[page.len() - 1] as usize;
+        let c = video.palette().entries[last];
+        assert_eq!(&rgb[rgb.len() - 3..], &[c.r, c.g, c.b]);
+        assert!(rgb == image::to_rgb(page, width, height, video.palette()));
+        fs::remove_dir_all(&options.dir).unwrap();
+    }
+
+    #[test]
+    fn native_screenshot_uses_the_palette() {
+        check_screenshot(320, 200);
+    }
+
+    #[test]
+    fn hires_screenshot_uses_the_palette() {
+        check_screenshot(640, 400);
+    }
+
+    #[test]
+    fn bundle_holds_every_page_and_the_palette() {
+        let video = video(320, 200);
+        let options = ScreenshotOptions {
+            dir: temp_path("bundle"),
+            bundle: true,
+        };
+        let path = save_screenshot(&video, &options).unwrap();
+        let bundle = path.with_extension("");
+        for (i, page) in video.pages().iter().enumerate() {
+            let (_, _, rgb) = read_png(&bundle.join(format!("page{}.png", i)));
+            assert!(rgb == image::to_rgb(&page.data, 320, 200, video.palette()));
+        }
+        let info = fs::read_to_string(bundle.join("palette.txt")).unwrap();
+        assert!(info.starts_with("# draw page 0, displayed page 1, back page 2\n"));
+        assert!(info.contains("\n15 #f00f0f\n"));
+        fs::remove_dir_all(&options.dir).unwrap();
+    }
+}
@@ -1,13 +1,12 @@
-use std::io::{Cursor, Result};
-use std::sync::mpsc::{sync_channel, Receiver};
-use std::sync::{Arc, RwLock};
+use std::io::{Cursor, Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
 
 use byteorder::{BigEndian, ReadBytesExt};
-use chrono;
-use log::{debug, error, trace};
-use timer::{Guard, Timer};
+use log::{debug, trace, warn};
 
-use crate::mixer::{MixerAudio, MixerChunk};
+use crate::mixer::{Mixer, MixerChunk, MixerHandle, SoundKind};
 
 pub struct SfxInstrument {
     data: Vec<u8>,
@@ -46,6 +45,10 @@ impl SfxModule {
             samples,
         }
     }
+
+    pub fn set_cur_pos(&mut self, cur_pos: usize) {
+        self.cur_pos = cur_pos % 1024;
+    }
 }
 
 pub enum PatternResult {
@@ -93,10 +96,14 @@ impl SfxPattern {
             }
         }
         let sample_start = 8;
+        let sample_buffer = sample
+            .data
+            .get(sample_start..)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Sample header is truncated"))?;
         Ok(SfxPattern {
             note1,
             note2,
-            sample_buffer: sample.data[sample_start..].to_vec(),
+            sample_buffer: sample_buffer.to_vec(),
             sample_len,
             loop_pos,
             loop_len,
@@ -105,26 +112,57 @@ impl SfxPattern {
     }
 }
 
+/// Sent from the audio callback to the VM while music plays
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MusicEvent {
+    Mark(i16),
+    /// The last order has been played
+    End,
+}
+
+/// What happens after the last order of a module
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SongEnd {
+    /// Stop the music and silence its channels, like the original
+    Stop,
+    /// Continue from the given order
+    Loop(u8),
+}
+
 pub struct SfxPlayer {
-    delay: i64,
+    delay: u16,
+    song_end: SongEnd,
     sfx_module: Option<SfxModule>,
-    timer: Timer,
-    timer_guard: Option<Guard>,
+    events_sender: Sender<MusicEvent>,
+    events: Receiver<MusicEvent>,
+    skipped_notes: Arc<AtomicU32>,
 }
 
 impl SfxPlayer {
     pub fn new() -> SfxPlayer {
+        let (events_sender, events) = channel();
         SfxPlayer {
             delay: 0,
+            song_end: SongEnd::Stop,
             sfx_module: None,
-            timer: Timer::new(),
-            timer_guard: None,
+            events_sender,
+            events,
+            skipped_notes: Arc::new(AtomicU32::new(0)),
         }
     }
 
-    pub fn set_events_delay(&mut self, delay: u16) {
+    pub fn set_events_delay(&mut self, mixer: &mut MixerHandle, delay: u16) {
         debug!("set_events_delay({})", delay);
-        self.delay = (delay as u32 * 60 / 7050) as i64;
+        self.delay = delay;
+        mixer.set_music_delay(delay);
+    }
+
+    pub fn set_song_end(&mut self, song_end: SongEnd) {
+        self.song_end = song_end;
+    }
+
+    pub fn song_end(&self) -> SongEnd {
+        self.song_end
     }
 
     pub fn set_sfx_module(&mut self, module: SfxModule) {
@@ -132,73 +170,162 @@ impl SfxPlayer {
         self.sfx_module = Some(module);
     }
 
-    pub fn start(&mut self, mixer: MixerAudio) -> Receiver<i16> {
-        let (tx, rx) = sync_channel::<i16>(0);
-        if let Some(sfx_module) = self.sfx_module.take() {
-            let sfx_module = Arc::new(RwLock::new(sfx_module));
-            self.timer_guard.replace(self.timer.schedule_repeating(
-                chrono::Duration::milliseconds(self.delay),
-                move || {
-                    if let Some(variable) =
-                        SfxPlayer::handle_events(sfx_module.clone(), mixer.clone())
-                    {
-                        tx.send(variable).unwrap();
-                    }
-                },
-            ));
+    /// Hands the module to the mixer, which plays it from the audio callback
+    pub fn start(&mut self, mixer: &mut MixerHandle) {
+        self.clear_events();
+        if let Some(module) = self.sfx_module.take() {
+            let song_end = match self.song_end {
+                SongEnd::Loop(order) if order >= module.num_order => {
+                    warn!(
+                        "Loop order {} is past the end of the song, looping from 0",
+                        order
+                    );
+                    SongEnd::Loop(0)
+                }
+                song_end => song_end,
+            };
+            let sequencer = SfxSequencer {
+                module,
+                delay: self.delay,
+                song_end,
+                elapsed: 0,
+                events: self.events_sender.clone(),
+                skipped_notes: self.skipped_notes.clone(),
+            };
+            mixer.play_music(sequencer);
         }
-        rx
     }
 
-    pub fn stop(&mut self) {
-        self.timer_guard.take();
+    pub fn stop(&mut self, mixer: &mut MixerHandle) {
+        mixer.stop_music();
+        self.clear_events();
+    }
+
+    /// Takes the oldest event sent by the music, without waiting
+    pub fn poll_event(&self) -> Option<MusicEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Returns how many notes the music could not play since the last call
+    pub fn take_skipped_notes(&self) -> u32 {
+        self.skipped_notes.swap(0, Ordering::Relaxed)
+    }
+
+    /// Queues an event as if the music had sent it
+    #[cfg(test)]
+    pub(crate) fn send_event(&self, event: MusicEvent) {
+        self.events_sender.send(event).unwrap();
+    }
+
+    /// Drops events of music that is no longer playing
+    fn clear_events(&self) {
+        while self.events.try_recv().is_ok() {}
     }
+}
+
+impl Default for SfxPlayer {
+    fn default() -> SfxPlayer {
+        SfxPlayer::new()
+    }
+}
+
+/// Steps through the rows of a module. It runs in the audio callback and
+/// counts rows in output samples, so row timing is exact.
+pub struct SfxSequencer {
+    module: SfxModule,
+    delay: u16,
+    song_end: SongEnd,
+    elapsed: u64,
+    events: Sender<MusicEvent>,
+    /// Counts malformed notes, the audio callback must not log or panic
+    skipped_notes: Arc<AtomicU32>,
+}
 
-    pub fn handle_events(sfx_module: Arc<RwLock<SfxModule>>, mixer: MixerAudio) -> Option<i16> {
-        let mut variable_value = None;
+impl SfxSequencer {
+    pub fn set_delay(&mut self, delay: u16) {
+        self.delay = delay;
+    }
+
+    pub fn position(&self) -> (u8, usize) {
+        (self.module.cur_order, self.module.cur_pos)
+    }
+
+    /// Advances by one output sample, returns true when the next row is due.
+    /// A row lasts `delay * 60 / 7050` milliseconds, which is kept as a
+    /// fraction so that rounding does not add up over a song.
+    pub fn advance(&mut self, sample_rate: u32) -> bool {
+        let row_length = self.delay as u64 * 60 * sample_rate as u64;
+        if row_length == 0 {
+            return false;
+        }
+        self.elapsed += 7050 * 1000;
+        if self.elapsed >= row_length {
+            self.elapsed -= row_length;
+            true
+        } else {
+            false
+        }
+    }
 
-        let mut sfx_module = sfx_module.write().expect("Expected non-poisoned RwLock");
-        let order = sfx_module.order_table[sfx_module.cur_order as usize] as usize;
-        let mut mixer_guard = mixer.0.write().expect("Expected non-poisoned RwLock");
+    /// Plays the next row. Returns false once the song has stopped.
+    pub fn handle_events(&mut self, mixer: &mut Mixer) -> bool {
+        let sfx_module = &mut self.module;
+        let order = sfx_module.order_table.get(sfx_module.cur_order as usize);
         for ch in 0..4 {
-            let start = sfx_module.cur_pos + order * 1024 + ch * 4;
-            trace!("Start: {}", start);
-            let pattern_data = Cursor::new(&sfx_module.data[start..start + 4]);
-            if let Ok(result) = SfxPlayer::handle_pattern(&sfx_module, ch as u8, pattern_data) {
+            let pattern_data = order.and_then(|order| {
+                let start = sfx_module.cur_pos + *order as usize * 1024 + ch * 4;
+                sfx_module.data.get(start..start + 4)
+            });
+            let pattern_data = match pattern_data {
+                Some(pattern_data) => Cursor::new(pattern_data),
+                None => {
+                    self.skipped_notes.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            if let Ok(result) = SfxSequencer::handle_pattern(sfx_module, ch as u8, pattern_data) {
                 match result {
-                    Some(PatternResult::StopChannel(channel)) => mixer_guard.stop_channel(channel),
-                    Some(PatternResult::MarkVariable(var)) => variable_value = Some(var as i16),
+                    Some(PatternResult::StopChannel(channel)) => mixer.stop_channel(channel),
+                    Some(PatternResult::MarkVariable(var)) => {
+                        // Never blocks, and the VM going away is fine
+                        let _ = self.events.send(MusicEvent::Mark(var as i16));
+                    }
                     Some(PatternResult::Pattern(channel, pat)) => {
-                        trace!("Playing music");
-                        assert!(pat.note1 >= 0x37);
-                        assert!(pat.note1 < 0x1000);
+                        if pat.note1 < 0x37 || pat.note1 >= 0x1000 {
+                            self.skipped_notes.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
                         let freq = (7_159_092 / (pat.note1 * 2) as u32) as u16;
                         let volume = pat.sample_volume;
                         let chunk = MixerChunk::from_sfx_pattern(pat);
-                        mixer_guard.play_channel(channel, chunk, freq, volume as u8);
+                        mixer.play_channel(channel, chunk, freq, volume as u8, SoundKind::Music);
                     }
                     None => {}
                 }
             } else {
-                error!("Error handling sfx pattern");
+                self.skipped_notes.fetch_add(1, Ordering::Relaxed);
             }
         }
 
-        let order = sfx_module.order_table[sfx_module.cur_order as usize] as usize;
         sfx_module.cur_pos += 4 * 4;
-        debug!(
-            "handle_events() order = 0x{:x} cur_pos = 0x{:x}",
-            order, sfx_module.cur_pos
-        );
         if sfx_module.cur_pos >= 1024 {
             sfx_module.cur_pos = 0;
-            let order = sfx_module.cur_order + 1;
-            if order == sfx_module.num_order {
-                //STOP PLAYING
+            sfx_module.cur_order += 1;
+            if sfx_module.cur_order >= sfx_module.num_order {
+                let _ = self.events.send(MusicEvent::End);
+                match self.song_end {
+                    // Checked against the module in SfxPlayer::start
+                    SongEnd::Loop(order) => sfx_module.cur_order = order,
+                    SongEnd::Stop => {
+                        for channel in 0..4 {
+                            mixer.stop_channel(channel);
+                        }
+                        return false;
+                    }
+                }
             }
-            sfx_module.cur_order = order;
         }
-        variable_value
+        true
     }
 
     fn handle_pattern(
@@ -208,20 +335,17 @@ impl SfxPlayer {
     ) -> Result<Option<PatternResult>> {
         let note1 = pattern_data.read_u16::<BigEndian>()?;
         let note2 = pattern_data.read_u16::<BigEndian>()?;
-        trace!("Note1: {}, Note2: {}", note1, note2);
         if note1 != 0xfffd {
             if note1 == 0xfffe {
-                trace!("Stop channel {}", channel);
                 return Ok(Some(PatternResult::StopChannel(channel)));
             }
             let sample_index = ((note2 & 0xf000) >> 12) as usize;
             if sample_index != 0 {
-                trace!("Have sample index");
-                if let Some(sample) = sfx_module.samples[sample_index - 1].as_ref() {
-                    trace!("Sample len: {}", sample.data.len());
+                let sample = sfx_module.samples.get(sample_index - 1);
+                if let Some(sample) = sample.and_then(Option::as_ref) {
                     return Ok(Some(PatternResult::Pattern(
                         channel,
-                        SfxPattern::from_notes(note1, note2, &sample)?,
+                        SfxPattern::from_notes(note1, note2, sample)?,
                     )));
                 }
             }
@@ -231,3 +355,122 @@ impl SfxPlayer {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mixer::OfflineOutput;
+
+    /// A module with a single order whose first row plays `note1` on channel
+    /// 0 and sets music mark 5 from channel 1
+    fn module(note1: u16) -> SfxModule {
+        let mut data = vec![0; 1024];
+        data[0..4].copy_from_slice(&[(note1 >> 8) as u8, note1 as u8, 0x10, 0x00]);
+        data[4..8].copy_from_slice(&[0xff, 0xfd, 0x00, 0x05]);
+        let sample = SfxInstrument::new(vec![0, 4, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8], 0x3f);
+        let mut samples: Vec<Option<SfxInstrument>> = (0..15).map(|_| None).collect();
+        samples[0] = Some(sample);
+        SfxModule::new(data, 0, 1, [0; 0x80], samples)
+    }
+
+    fn play_for(module: SfxModule, song_end: SongEnd, ms: u64) -> (SfxPlayer, MixerHandle) {
+        let (mut mixer, audio) = MixerHandle::new();
+        let mut output = OfflineOutput::new(audio);
+        let mut player = SfxPlayer::new();
+        player.set_events_delay(&mut mixer, 120);
+        player.set_song_end(song_end);
+        player.set_sfx_module(module);
+        player.start(&mut mixer);
+        output.render_until(0);
+        output.render_until(ms);
+        (player, mixer)
+    }
+
+    /// Plays the first row of `module`
+    fn play(module: SfxModule) -> SfxPlayer {
+        play_for(module, SongEnd::Stop, 2).0
+    }
+
+    fn events(player: &SfxPlayer) -> Vec<MusicEvent> {
+        std::iter::from_fn(|| player.poll_event()).collect()
+    }
+
+    #[test]
+    fn song_end_stops_the_music() {
+        let (player, mixer) = play_for(module(0x100), SongEnd::Stop, 200);
+        assert_eq!(events(&player), [MusicEvent::Mark(5), MusicEvent::End]);
+        assert_eq!(mixer.music_position(), None);
+    }
+
+    #[test]
+    fn song_end_loops_from_0_when_the_order_is_past_the_end() {
+        let (player, mixer) = play_for(module(0x100), SongEnd::Loop(5), 100);
+        let events = events(&player);
+        assert_eq!(
+            events[..3],
+            [MusicEvent::Mark(5), MusicEvent::End, MusicEvent::Mark(5)]
+        );
+        assert_eq!(mixer.music_position().map(|(order, _)| order), Some(0));
+    }
+
+    #[test]
+    fn paused_music_sends_no_marks() {
+        let (mut mixer, audio) = MixerHandle::new();
+        let mut output = OfflineOutput::new(audio);
+        mixer.set_paused(true);
+        let mut player = SfxPlayer::new();
+        player.set_events_delay(&mut mixer, 120);
+        player.set_sfx_module(module(0x100));
+        player.start(&mut mixer);
+        output.render_until(0);
+        output.render_until(10);
+        assert_eq!(player.poll_event(), None);
+        assert_eq!(mixer.music_position(), Some((0, 0)));
+    }
+
+    #[test]
+    fn valid_notes_are_played() {
+        let player = play(module(0x100));
+        assert_eq!(player.take_skipped_notes(), 0);
+        assert_eq!(player.poll_event(), Some(MusicEvent::Mark(5)));
+    }
+
+    #[test]
+    fn invalid_notes_are_skipped_and_counted() {
+        for note1 in &[0x10, 0x1000] {
+            let player = play(module(*note1));
+            assert_eq!(player.take_skipped_notes(), 1);
+            assert_eq!(player.take_skipped_notes(), 0);
+            assert_eq!(player.poll_event(), Some(MusicEvent::Mark(5)));
+        }
+    }
+
+    #[test]
+    fn truncated_samples_are_skipped_and_counted() {
+        for len in &[2, 6] {
+            let mut module = module(0x100);
+            module.samples[0] = Some(SfxInstrument::new(vec![0; *len], 0x3f));
+            let player = play(module);
+            assert_eq!(player.take_skipped_notes(), 1);
+            assert_eq!(player.poll_event(), Some(MusicEvent::Mark(5)));
+        }
+    }
+
+    #[test]
+    fn missing_pattern_data_is_skipped_and_counted() {
+        let mut module = module(0x100);
+        module.data.truncate(6);
+        let player = play(module);
+        // Only the first channel is complete
+        assert_eq!(player.take_skipped_notes(), 3);
+    }
+
+    #[test]
+    fn orders_past_the_table_are_skipped_and_counted() {
+        let module = module(0x100);
+        let module = SfxModule::new(module.data, 0x80, 0x81, [0; 0x80], module.samples);
+        let player = play(module);
+        assert_eq!(player.take_skipped_notes(), 4);
+        assert_eq!(player.poll_event(), None);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+const STATE_MAGIC: &[u8; 4] = b"AWSS";
+
+/// Bump whenever the layout written by `VirtualMachine::save_state` changes
+pub const STATE_VERSION: u16 = 1;
+
+/// Directory holding the save state slots, created if missing
+pub fn state_dir() -> Result<PathBuf> {
+    let data_dir = dirs::data_dir().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "Could not determine user data directory",
+        )
+    })?;
+    let dir = data_dir.join("anotherworld").join("states");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+pub fn slot_path(slot: i8) -> Result<PathBuf> {
+    Ok(state_dir()?.join(format!("slot{}.state", slot)))
+}
+
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(STATE_MAGIC)?;
+    writer.write_u16::<BigEndian>(STATE_VERSION)
+}
+
+pub fn read_header<R: Read>(reader: &mut R) -> Result<()> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != STATE_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a save state file"));
+    }
+    let version = reader.read_u16::<BigEndian>()?;
+    if version != STATE_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Unsupported save state version {}, expected {}",
+                version, STATE_VERSION
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_round_trips() {
+        let mut header = Vec::new();
+        write_header(&mut header).unwrap();
+        assert_eq!(header.len(), 6);
+        read_header(&mut Cursor::new(&header)).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let err = read_header(&mut Cursor::new(b"\x89PNG\r\n")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut header = STATE_MAGIC.to_vec();
+        header.write_u16::<BigEndian>(STATE_VERSION + 1).unwrap();
+        let err = read_header(&mut Cursor::new(&header)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,62 +1,487 @@
-use log::debug;
-use std::sync::{Arc, RwLock};
-use std::{thread, time};
+use log::{debug, info, warn};
+use std::cmp;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use sdl2::audio::{AudioDevice, AudioSpecDesired};
+use sdl2::controller::{Axis, Button, GameController};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::{Color, Palette, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::WindowCanvas;
 use sdl2::surface::Surface;
-use sdl2::EventPump;
+use sdl2::video::FullscreenType;
+use sdl2::{EventPump, GameControllerSubsystem};
 
+use crate::bindings::{Action, KeyBindings};
+use crate::font;
 use crate::mixer;
 use crate::player::{PlayerDirection, PlayerInput};
 use crate::video;
 
+/// Platform services used by the virtual machine: presenting frames, polling
+/// input and audio output.
+pub trait Backend {
+    fn set_palette(&mut self, palette: &video::Palette);
+    fn update_display(&mut self, page: &video::Page);
+    fn process_events(&mut self) -> PlayerInput;
+    /// Switches between typed text and key bindings for keyboard input
+    fn set_text_input(&mut self, enabled: bool);
+    /// Starts playing the mixer. Hands it back if there is no audio output,
+    /// in which case the caller has to pull the mixer itself.
+    fn start_audio(&mut self, audio: mixer::MixerAudio) -> Option<mixer::MixerAudio>;
+    /// Stops the audio output and hands back the mixer it was playing
+    fn stop_audio(&mut self) -> Option<mixer::MixerAudio>;
+    /// Shows a short message over the picture for a moment
+    fn show_message(&mut self, message: &str);
+}
+
+/// Analog stick deadzone in percent of the stick range
+pub const DEFAULT_DEADZONE_PERCENT: u8 = 25;
+
+/// How long a message stays over the picture
+const MESSAGE_DURATION: Duration = Duration::from_millis(1500);
+
+/// How the game picture is scaled to the window
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scaling {
+    /// Largest size that keeps the square pixel aspect ratio
+    Fit,
+    /// Like `Fit`, but only whole multiples of the game resolution
+    Integer,
+    /// Stretches to 4:3 like the non-square pixels of the original displays
+    Aspect,
+}
+
+impl FromStr for Scaling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fit" => Ok(Scaling::Fit),
+            "integer" => Ok(Scaling::Integer),
+            "aspect" => Ok(Scaling::Aspect),
+            _ => Err(format!("Unknown scaling mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WindowSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for WindowSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, 'x');
+        let mut next = || -> Result<u32, String> {
+            parts
+                .next()
+                .and_then(|p| p.trim().parse().ok())
+                .filter(|v| *v > 0)
+                .ok_or_else(|| format!("Expected window size as WIDTHxHEIGHT, got {}", s))
+        };
+        Ok(WindowSize {
+            width: next()?,
+            height: next()?,
+        })
+    }
+}
+
+pub struct WindowOptions {
+    pub size: WindowSize,
+    pub fullscreen: bool,
+    pub scaling: Scaling,
+}
+
+impl WindowOptions {
+    pub fn new() -> WindowOptions {
+        WindowOptions {
+            size: WindowSize {
+                width: 1280,
+                height: 800,
+            },
+            fullscreen: false,
+            scaling: Scaling::Fit,
+        }
+    }
+}
+
+impl Default for WindowOptions {
+    fn default() -> WindowOptions {
+        WindowOptions::new()
+    }
+}
+
+/// Centers the game picture of size `game` in an output of size `output`
+fn scaled_rect(scaling: Scaling, game: (usize, usize), output: (u32, u32)) -> Rect {
+    let (output_width, output_height) = output;
+    let (width, height) = match scaling {
+        Scaling::Aspect => (game.0 as f64, game.0 as f64 * 3.0 / 4.0),
+        Scaling::Fit | Scaling::Integer => (game.0 as f64, game.1 as f64),
+    };
+    let mut scale = f64::min(output_width as f64 / width, output_height as f64 / height);
+    if scaling == Scaling::Integer {
+        scale = scale.floor().max(1.0);
+    }
+    let w = (width * scale) as u32;
+    let h = (height * scale) as u32;
+    let x = (output_width as i32 - w as i32) / 2;
+    let y = (output_height as i32 - h as i32) / 2;
+    Rect::new(x, y, w, h)
+}
+
+/// Keys that produce a printable character, these are left to text input
+/// while it is enabled
+fn is_text_key(keycode: Keycode) -> bool {
+    (0x20..0x7f).contains(&(keycode as i32))
+}
+
+/// Stick position past which it counts as a direction
+fn deadzone(percent: u8) -> i16 {
+    (i16::MAX as i32 * percent.min(100) as i32 / 100) as i16
+}
+
+#[derive(Debug, PartialEq)]
+enum MenuAction {
+    Pause,
+    Code,
+}
+
+/// Start and back buttons. Start on its own pauses when released, pressing
+/// both together opens code entry.
+#[derive(Default)]
+struct MenuButtons {
+    start: bool,
+    back: bool,
+    /// Both were held since start was pressed
+    chorded: bool,
+}
+
+impl MenuButtons {
+    fn update(&mut self, button: Button, pressed: bool) -> Option<MenuAction> {
+        match button {
+            Button::Start => self.start = pressed,
+            Button::Back => self.back = pressed,
+            _ => return None,
+        }
+        if self.start && self.back {
+            if pressed && !self.chorded {
+                self.chorded = true;
+                return Some(MenuAction::Code);
+            }
+            return None;
+        }
+        let action = if button == Button::Start && !pressed && !self.chorded {
+            Some(MenuAction::Pause)
+        } else {
+            None
+        };
+        if !self.start && !self.back {
+            self.chorded = false;
+        }
+        action
+    }
+}
+
+struct ControllerState {
+    _controller: GameController,
+    dpad: PlayerDirection,
+    stick: PlayerDirection,
+    face_buttons: u8,
+    menu_buttons: MenuButtons,
+}
+
+impl ControllerState {
+    fn new(controller: GameController) -> ControllerState {
+        ControllerState {
+            _controller: controller,
+            dpad: PlayerDirection::empty(),
+            stick: PlayerDirection::empty(),
+            face_buttons: 0,
+            menu_buttons: MenuButtons::default(),
+        }
+    }
+}
+
 pub struct SDLSys {
     sdl_context: sdl2::Sdl,
     surface: Surface<'static>,
     canvas: WindowCanvas,
     audio_device: Option<AudioDevice<mixer::MixerAudio>>,
-    timestamp: time::Instant,
+    sample_rate: Option<u32>,
     event_pump: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: HashMap<u32, ControllerState>,
+    deadzone: i16,
+    bindings: KeyBindings,
     player_input: PlayerInput,
+    keyboard_direction: PlayerDirection,
+    keyboard_button: bool,
+    text_input: bool,
+    fullscreen: bool,
+    scaling: Scaling,
+    message: Option<(String, Instant)>,
     width: usize,
     height: usize,
 }
 
 impl SDLSys {
     pub fn new(sdl_context: sdl2::Sdl, width: usize, height: usize) -> SDLSys {
+        SDLSys::with_window_options(sdl_context, width, height, &WindowOptions::new())
+    }
+
+    pub fn with_window_options(
+        sdl_context: sdl2::Sdl,
+        width: usize,
+        height: usize,
+        options: &WindowOptions,
+    ) -> SDLSys {
         let video_subsystem = sdl_context.video().unwrap();
 
-        let window = video_subsystem
-            .window("Another world", 1280, 800)
-            .position_centered()
-            .resizable()
-            .build()
-            .unwrap();
+        let mut window_builder =
+            video_subsystem.window("Another world", options.size.width, options.size.height);
+        window_builder.position_centered().resizable();
+        if options.fullscreen {
+            window_builder.fullscreen_desktop();
+        }
+        let window = window_builder.build().unwrap();
 
-        let mut canvas = window.into_canvas().build().expect("Expected canvas");
-        canvas
-            .set_logical_size(width as u32, height as u32)
-            .expect("Expected logical size");
+        let canvas = window.into_canvas().build().expect("Expected canvas");
         let event_pump = sdl_context.event_pump().unwrap();
+        // Connected controllers are announced with ControllerDeviceAdded events
+        let controller_subsystem = sdl_context.game_controller().unwrap();
+        // SDL starts with text input enabled, it is only wanted on the
+        // password screen
+        video_subsystem.text_input().stop();
         SDLSys {
             sdl_context,
             surface: Surface::new(width as u32, height as u32, PixelFormatEnum::Index8).unwrap(),
             canvas,
             audio_device: None,
-            timestamp: time::Instant::now(),
+            sample_rate: None,
             event_pump,
+            controller_subsystem,
+            controllers: HashMap::new(),
+            deadzone: deadzone(DEFAULT_DEADZONE_PERCENT),
+            bindings: KeyBindings::new(),
             player_input: PlayerInput::new(),
+            keyboard_direction: PlayerDirection::empty(),
+            keyboard_button: false,
+            text_input: false,
+            fullscreen: options.fullscreen,
+            scaling: options.scaling,
+            message: None,
             width,
             height,
         }
     }
 
-    pub fn set_palette(&mut self, palette: &video::Palette) {
+    /// Sets how far the analog stick has to be pushed, in percent, before
+    /// it counts as a direction
+    pub fn set_deadzone(&mut self, percent: u8) {
+        self.deadzone = deadzone(percent);
+    }
+
+    /// Output rate to ask the audio device for, `None` takes its default
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Area of the window the picture is drawn to, centered with black
+    /// borders where it does not fill the window
+    fn display_rect(&self) -> Rect {
+        let (output_width, output_height) = self
+            .canvas
+            .output_size()
+            .unwrap_or((self.width as u32, self.height as u32));
+        scaled_rect(
+            self.scaling,
+            (self.width, self.height),
+            (output_width, output_height),
+        )
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        self.fullscreen = !self.fullscreen;
+        let fullscreen_type = if self.fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        if let Err(e) = self.canvas.window_mut().set_fullscreen(fullscreen_type) {
+            warn!("Could not change fullscreen mode: {}", e);
+            self.fullscreen = !self.fullscreen;
+        }
+    }
+
+    /// Draws the message in the game font on a black box in the top left
+    /// corner, with font pixels scaled like the picture
+    fn draw_message(&mut self) {
+        let message = match self.message {
+            Some((ref message, shown)) if shown.elapsed() < MESSAGE_DURATION => message.clone(),
+            _ => {
+                self.message = None;
+                return;
+            }
+        };
+        let display_rect = self.display_rect();
+        let pixel = cmp::max(display_rect.height() / 200, 1);
+        let x = display_rect.x() + 4 * pixel as i32;
+        let y = display_rect.y() + 4 * pixel as i32;
+
+        let mut rects = Vec::new();
+        for (n, c) in message.chars().enumerate() {
+            let c = if (' '..='\x7f').contains(&c) { c } else { '?' };
+            let offset = (c as usize - ' ' as usize) * 8;
+            for (j, row) in font::FONT[offset..offset + 8].iter().enumerate() {
+                for i in 0..8 {
+                    if (row << i) & 0x80 != 0 {
+                        rects.push(Rect::new(
+                            x + ((n * 8 + i) as u32 * pixel) as i32,
+                            y + (j as u32 * pixel) as i32,
+                            pixel,
+                            pixel,
+                        ));
+                    }
+                }
+            }
+        }
+        let background = Rect::new(
+            x - 2 * pixel as i32,
+            y - 2 * pixel as i32,
+            (message.chars().count() as u32 * 8 + 4) * pixel,
+            12 * pixel,
+        );
+
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        if let Err(e) = self.canvas.fill_rect(background) {
+            warn!("Could not draw message: {}", e);
+        }
+        self.canvas.set_draw_color(Color::RGB(255, 255, 255));
+        if let Err(e) = self.canvas.fill_rects(&rects) {
+            warn!("Could not draw message: {}", e);
+        }
+        // The draw color is also what the canvas is cleared with
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+    }
+
+    pub fn set_bindings(&mut self, bindings: KeyBindings) {
+        self.bindings = bindings;
+    }
+
+    fn key_action(&mut self, action: Action, pressed: bool) {
+        let direction = match action {
+            Action::Left => PlayerDirection::LEFT,
+            Action::Right => PlayerDirection::RIGHT,
+            Action::Up => PlayerDirection::UP,
+            Action::Down => PlayerDirection::DOWN,
+            Action::Button => {
+                self.keyboard_button = pressed;
+                return;
+            }
+            Action::Rewind => {
+                self.player_input.rewind = pressed;
+                return;
+            }
+            _ => {
+                if pressed {
+                    let input = &mut self.player_input;
+                    match action {
+                        Action::Code => input.code = true,
+                        Action::Pause => input.pause = !input.pause,
+                        Action::Quit => input.quit = true,
+                        Action::Save => input.save = true,
+                        Action::Load => input.load = true,
+                        Action::Slot(slot) => {
+                            input.state_slot = slot;
+                            debug!("Selected state slot {}", slot);
+                        }
+                        Action::Turbo => input.turbo = !input.turbo,
+                        Action::Fullscreen => self.toggle_fullscreen(),
+                        Action::Screenshot => input.screenshot = true,
+                        Action::Replay => input.replay = true,
+                        Action::VolumeSelect => input.volume_select = true,
+                        Action::VolumeUp => input.volume_up = true,
+                        Action::VolumeDown => input.volume_down = true,
+                        Action::Mute => input.mute = true,
+                        _ => {}
+                    }
+                }
+                return;
+            }
+        };
+        self.keyboard_direction.set(direction, pressed);
+    }
+
+    fn open_controller(&mut self, joystick_index: u32) {
+        match self.controller_subsystem.open(joystick_index) {
+            Ok(controller) => {
+                info!("Opened controller {}", controller.name());
+                self.controllers
+                    .insert(controller.instance_id(), ControllerState::new(controller));
+            }
+            Err(e) => warn!("Could not open controller {}: {}", joystick_index, e),
+        }
+    }
+
+    fn controller_button(&mut self, id: u32, button: Button, pressed: bool) {
+        let controller = match self.controllers.get_mut(&id) {
+            Some(controller) => controller,
+            None => return,
+        };
+        let direction = match button {
+            Button::DPadLeft => PlayerDirection::LEFT,
+            Button::DPadRight => PlayerDirection::RIGHT,
+            Button::DPadUp => PlayerDirection::UP,
+            Button::DPadDown => PlayerDirection::DOWN,
+            Button::A | Button::B | Button::X | Button::Y => {
+                let mask = 1 << (button as u8);
+                if pressed {
+                    controller.face_buttons |= mask;
+                } else {
+                    controller.face_buttons &= !mask;
+                }
+                return;
+            }
+            Button::Start | Button::Back => {
+                match controller.menu_buttons.update(button, pressed) {
+                    Some(MenuAction::Pause) => {
+                        self.player_input.pause = !self.player_input.pause;
+                    }
+                    Some(MenuAction::Code) => self.player_input.code = true,
+                    None => {}
+                }
+                return;
+            }
+            _ => return,
+        };
+        controller.dpad.set(direction, pressed);
+    }
+
+    fn controller_axis(&mut self, id: u32, axis: Axis, value: i16) {
+        let deadzone = self.deadzone;
+        let controller = match self.controllers.get_mut(&id) {
+            Some(controller) => controller,
+            None => return,
+        };
+        let (negative, positive) = match axis {
+            Axis::LeftX => (PlayerDirection::LEFT, PlayerDirection::RIGHT),
+            Axis::LeftY => (PlayerDirection::UP, PlayerDirection::DOWN),
+            _ => return,
+        };
+        controller.stick.set(negative, value < -deadzone);
+        controller.stick.set(positive, value > deadzone);
+    }
+}
+
+impl Backend for SDLSys {
+    fn set_palette(&mut self, palette: &video::Palette) {
         debug!("set_palette()");
         let colors: Vec<Color> = palette
             .entries
@@ -68,7 +493,7 @@ impl SDLSys {
         self.surface.set_palette(&sdl_palette).unwrap();
     }
 
-    pub fn update_display(&mut self, page: &video::Page) {
+    fn update_display(&mut self, page: &video::Page) {
         debug!("update_display()");
         let pitch = self.surface.pitch() as usize;
         let width = self.width;
@@ -86,129 +511,197 @@ impl SDLSys {
             .create_texture_from_surface(&*self.surface)
             .unwrap();
         self.canvas.clear();
+        let display_rect = self.display_rect();
         self.canvas
-            .copy(
-                &texture,
-                None,
-                Some(Rect::new(0, 0, width as u32, height as u32)),
-            )
+            .copy(&texture, None, Some(display_rect))
             .unwrap();
+        self.draw_message();
         self.canvas.present();
     }
 
-    pub fn sleep(&self, ms: u64) {
-        let duration = time::Duration::from_millis(ms);
-        thread::sleep(duration);
-    }
-
-    pub fn get_timestamp(&self) -> u64 {
-        (self.timestamp.elapsed().as_millis() & std::u64::MAX as u128) as u64
+    fn set_text_input(&mut self, enabled: bool) {
+        debug!("set_text_input({})", enabled);
+        let text_input = self.sdl_context.video().unwrap().text_input();
+        if enabled {
+            text_input.start();
+        } else {
+            text_input.stop();
+        }
+        self.text_input = enabled;
     }
 
-    pub fn start_audio(&mut self, audio: Arc<RwLock<mixer::Mixer>>) {
+    fn start_audio(&mut self, mut audio: mixer::MixerAudio) -> Option<mixer::MixerAudio> {
         debug!("Starting audio");
         let audio_subsystem = self.sdl_context.audio().unwrap();
 
         let desired_spec = AudioSpecDesired {
-            freq: Some(mixer::SOUND_SAMPLE_RATE as i32),
-            channels: Some(1),
+            freq: self.sample_rate.map(|rate| rate as i32),
+            channels: Some(mixer::OUTPUT_CHANNELS),
             samples: None,
         };
 
         let device = audio_subsystem
             .open_playback(None, &desired_spec, |spec| {
                 debug!("Actual spec: {:?}", spec);
-                mixer::MixerAudio(audio)
+                audio.set_sample_rate(spec.freq as u32);
+                audio
             })
             .unwrap();
 
         device.resume();
         self.audio_device = Some(device);
+        None
+    }
+
+    fn stop_audio(&mut self) -> Option<mixer::MixerAudio> {
+        debug!("Stopping audio");
+        self.audio_device
+            .take()
+            .map(|device| device.close_and_get_callback())
     }
 
-    pub fn process_events(&mut self) -> PlayerInput {
+    fn show_message(&mut self, message: &str) {
+        self.message = Some((message.to_string(), Instant::now()));
+    }
+
+    fn process_events(&mut self) -> PlayerInput {
         let mut last_char = '\0';
-        for event in self.event_pump.poll_iter() {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
+                Event::Quit { .. } => self.player_input.quit = true,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat,
                     ..
-                } => self.player_input.quit = true,
-                Event::KeyDown { keycode, .. } => match keycode.unwrap() {
-                    Keycode::Left => self.player_input.direction |= PlayerDirection::LEFT,
-                    Keycode::Right => self.player_input.direction |= PlayerDirection::RIGHT,
-                    Keycode::Up => self.player_input.direction |= PlayerDirection::UP,
-                    Keycode::Down => self.player_input.direction |= PlayerDirection::DOWN,
-                    Keycode::LShift | Keycode::Space | Keycode::Return => {
-                        self.player_input.button = true
-                    }
-                    Keycode::Backspace => last_char = '\x08',
-                    Keycode::A => {
-                        self.player_input.direction |= PlayerDirection::LEFT;
-                        last_char = 'A';
-                    }
-                    Keycode::B => last_char = 'B',
-                    Keycode::C => {
-                        self.player_input.code = true;
-                        last_char = 'C';
-                    }
-                    Keycode::D => {
-                        self.player_input.direction |= PlayerDirection::RIGHT;
-                        last_char = 'D';
+                } => {
+                    if keycode == Keycode::Backspace {
+                        last_char = '\x08';
                     }
-                    Keycode::E => last_char = 'E',
-                    Keycode::F => last_char = 'F',
-                    Keycode::G => last_char = 'G',
-                    Keycode::H => last_char = 'H',
-                    Keycode::I => last_char = 'I',
-                    Keycode::J => last_char = 'J',
-                    Keycode::K => last_char = 'K',
-                    Keycode::L => last_char = 'L',
-                    Keycode::M => last_char = 'M',
-                    Keycode::N => last_char = 'N',
-                    Keycode::O => last_char = 'O',
-                    Keycode::P => last_char = 'P',
-                    Keycode::Q => last_char = 'Q',
-                    Keycode::R => last_char = 'R',
-                    Keycode::S => {
-                        self.player_input.direction |= PlayerDirection::DOWN;
-                        last_char = 'S';
+                    if self.text_input && is_text_key(keycode) {
+                        continue;
                     }
-                    Keycode::T => last_char = 'T',
-                    Keycode::U => last_char = 'U',
-                    Keycode::V => last_char = 'V',
-                    Keycode::W => {
-                        self.player_input.direction |= PlayerDirection::UP;
-                        last_char = 'W';
+                    if let Some(action) = self.bindings.action(keycode) {
+                        if !repeat {
+                            self.key_action(action, true);
+                        }
                     }
-                    Keycode::X => last_char = 'X',
-                    Keycode::Y => last_char = 'Y',
-                    Keycode::Z => last_char = 'Z',
-                    _ => {}
-                },
-                Event::KeyUp { keycode, .. } => match keycode.unwrap() {
-                    Keycode::Left | Keycode::A => {
-                        self.player_input.direction &= !PlayerDirection::LEFT
-                    }
-                    Keycode::Right | Keycode::D => {
-                        self.player_input.direction &= !PlayerDirection::RIGHT
-                    }
-                    Keycode::Up | Keycode::W => self.player_input.direction &= !PlayerDirection::UP,
-                    Keycode::Down | Keycode::S => {
-                        self.player_input.direction &= !PlayerDirection::DOWN
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(action) = self.bindings.action(keycode) {
+                        self.key_action(action, false);
                     }
-                    Keycode::LShift | Keycode::Space | Keycode::Return => {
-                        self.player_input.button = false
+                }
+                Event::TextInput { text, .. } => {
+                    if let Some(c) = text.chars().next() {
+                        last_char = c.to_ascii_uppercase();
                     }
-                    _ => {}
-                },
+                }
+                Event::ControllerDeviceAdded { which, .. } => self.open_controller(which),
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    info!("Controller {} removed", which);
+                    self.controllers.remove(&which);
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    self.controller_button(which, button, true)
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    self.controller_button(which, button, false)
+                }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => self.controller_axis(which, axis, value),
                 _ => {}
             }
         }
+
+        let mut direction = self.keyboard_direction;
+        let mut button = self.keyboard_button;
+        for controller in self.controllers.values() {
+            direction |= controller.dpad | controller.stick;
+            button |= controller.face_buttons != 0;
+        }
+        self.player_input.direction = direction;
+        self.player_input.button = button;
         self.player_input.last_char = last_char;
         let result = self.player_input;
         self.player_input.code = false;
+        self.player_input.save = false;
+        self.player_input.load = false;
+        self.player_input.screenshot = false;
+        self.player_input.replay = false;
+        self.player_input.volume_select = false;
+        self.player_input.volume_up = false;
+        self.player_input.volume_down = false;
+        self.player_input.mute = false;
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_alone_pauses_on_release() {
+        let mut buttons = MenuButtons::default();
+        assert_eq!(buttons.update(Button::Start, true), None);
+        assert_eq!(
+            buttons.update(Button::Start, false),
+            Some(MenuAction::Pause)
+        );
+        assert_eq!(buttons.update(Button::Back, true), None);
+        assert_eq!(buttons.update(Button::Back, false), None);
+    }
+
+    #[test]
+    fn start_and_back_together_open_code_entry() {
+        let mut buttons = MenuButtons::default();
+        assert_eq!(buttons.update(Button::Back, true), None);
+        assert_eq!(buttons.update(Button::Start, true), Some(MenuAction::Code));
+        assert_eq!(buttons.update(Button::Back, false), None);
+        assert_eq!(buttons.update(Button::Start, false), None);
+
+        assert_eq!(buttons.update(Button::Start, true), None);
+        assert_eq!(buttons.update(Button::Back, true), Some(MenuAction::Code));
+        assert_eq!(buttons.update(Button::Start, false), None);
+        assert_eq!(buttons.update(Button::Back, false), None);
+        assert_eq!(buttons.update(Button::Start, true), None);
+        assert_eq!(
+            buttons.update(Button::Start, false),
+            Some(MenuAction::Pause)
+        );
+    }
+
+    #[test]
+    fn deadzone_is_a_share_of_the_stick_range() {
+        assert_eq!(deadzone(0), 0);
+        assert_eq!(deadzone(DEFAULT_DEADZONE_PERCENT), 8191);
+        assert_eq!(deadzone(150), i16::MAX);
+    }
+
+    #[test]
+    fn fit_fills_the_matching_side() {
+        let rect = scaled_rect(Scaling::Fit, (320, 200), (1280, 1000));
+        assert_eq!(rect, Rect::new(0, 100, 1280, 800));
+    }
+
+    #[test]
+    fn integer_scaling_rounds_down() {
+        let rect = scaled_rect(Scaling::Integer, (320, 200), (1000, 700));
+        assert_eq!(rect, Rect::new(20, 50, 960, 600));
+        let rect = scaled_rect(Scaling::Integer, (320, 200), (200, 100));
+        assert_eq!(rect, Rect::new(-60, -50, 320, 200));
+    }
+
+    #[test]
+    fn aspect_stretches_to_four_by_three() {
+        let rect = scaled_rect(Scaling::Aspect, (320, 200), (1280, 960));
+        assert_eq!(rect, Rect::new(0, 0, 1280, 960));
+        let rect = scaled_rect(Scaling::Aspect, (640, 400), (1920, 1080));
+        assert_eq!(rect, Rect::new(240, 0, 1440, 1080));
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use crate::mixer::MixerAudio;
+use crate::player::PlayerInput;
+use crate::sys::Backend;
+use crate::video::{Page, Palette};
+
+/// Path in the temp directory for a file or directory of a test, unique to
+/// this test run. Anything left there by an earlier run is removed.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("anotherworld-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Backend that hands out scripted input and discards all output
+pub struct StubSys {
+    inputs: VecDeque<PlayerInput>,
+}
+
+impl StubSys {
+    pub fn new(inputs: Vec<PlayerInput>) -> StubSys {
+        StubSys {
+            inputs: inputs.into(),
+        }
+    }
+}
+
+impl Backend for StubSys {
+    fn set_palette(&mut self, _palette: &Palette) {}
+
+    fn update_display(&mut self, _page: &Page) {}
+
+    fn process_events(&mut self) -> PlayerInput {
+        self.inputs.pop_front().unwrap_or_default()
+    }
+
+    fn set_text_input(&mut self, _enabled: bool) {}
+
+    fn start_audio(&mut self, audio: MixerAudio) -> Option<MixerAudio> {
+        Some(audio)
+    }
+
+    fn stop_audio(&mut self) -> Option<MixerAudio> {
+        None
+    }
+
+    fn show_message(&mut self, _message: &str) {}
+}
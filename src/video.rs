@@ -1,12 +1,13 @@
 use log::{debug, warn};
 use std::cmp;
-use std::io::{Cursor, Result};
+use std::io::prelude::*;
+use std::io::{Cursor, Error, ErrorKind, Result};
 
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::font::FONT;
 use crate::strings::STRINGS_TABLE_ENG;
-use crate::sys::SDLSys;
+use crate::sys::Backend;
 
 const MAX_POINTS: usize = 50;
 const NUM_COLORS: usize = 16;
@@ -19,11 +20,23 @@ pub struct Color {
     pub a: u8,
 }
 
+#[derive(Copy, Clone)]
 pub struct Palette {
     pub entries: [Color; NUM_COLORS],
 }
 
 impl Palette {
+    pub fn new() -> Palette {
+        Palette {
+            entries: [Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0xff,
+            }; NUM_COLORS],
+        }
+    }
+
     pub fn from_bytes(buffer: &[u8]) -> Palette {
         let mut entries = [Color {
             r: 0,
@@ -44,6 +57,12 @@ impl Palette {
     }
 }
 
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new()
+    }
+}
+
 #[derive(Debug)]
 pub struct Point {
     pub x: i32,
@@ -101,6 +120,7 @@ fn calc_step(p1: &Point, p2: &Point) -> (i32, u16) {
 pub struct Video {
     pages: [Page; 4],
     pub palette_requested: Option<Palette>,
+    palette: Palette,
     cur_page_ptr1: usize,
     cur_page_ptr2: usize,
     cur_page_ptr3: usize,
@@ -115,6 +135,7 @@ impl Video {
         Video {
             pages: [page.clone(), page.clone(), page.clone(), page],
             palette_requested: None,
+            palette: Palette::new(),
             cur_page_ptr1: 2,
             cur_page_ptr2: 2,
             cur_page_ptr3: 1,
@@ -123,7 +144,7 @@ impl Video {
         }
     }
 
-    pub fn update_display(&mut self, sys: &mut SDLSys, page_id: u8) {
+    pub fn update_display(&mut self, sys: &mut dyn Backend, page_id: u8) {
         debug!("update_display({})", page_id);
         if page_id != 0xfe {
             if page_id == 0xff {
@@ -137,10 +158,73 @@ impl Video {
 
         if let Some(palette) = self.palette_requested.take() {
             sys.set_palette(&palette);
+            self.palette = palette;
         }
         sys.update_display(&self.pages[self.cur_page_ptr2]);
     }
 
+    pub fn save_state<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.width as u16)?;
+        writer.write_u16::<BigEndian>(self.height as u16)?;
+        writer.write_u8(self.cur_page_ptr1 as u8)?;
+        writer.write_u8(self.cur_page_ptr2 as u8)?;
+        writer.write_u8(self.cur_page_ptr3 as u8)?;
+        let palette = self.palette_requested.as_ref().unwrap_or(&self.palette);
+        for c in palette.entries.iter() {
+            writer.write_all(&[c.r, c.g, c.b])?;
+        }
+        for page in self.pages.iter() {
+            writer.write_all(&page.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn load_state<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        let width = reader.read_u16::<BigEndian>()? as usize;
+        let height = reader.read_u16::<BigEndian>()? as usize;
+        if width != self.width || height != self.height {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "State has {}x{} pages, expected {}x{}",
+                    width, height, self.width, self.height
+                ),
+            ));
+        }
+        self.cur_page_ptr1 = reader.read_u8()? as usize & 3;
+        self.cur_page_ptr2 = reader.read_u8()? as usize & 3;
+        self.cur_page_ptr3 = reader.read_u8()? as usize & 3;
+        let mut palette = Palette::new();
+        for c in palette.entries.iter_mut() {
+            c.r = reader.read_u8()?;
+            c.g = reader.read_u8()?;
+            c.b = reader.read_u8()?;
+        }
+        self.palette_requested = Some(palette);
+        for page in self.pages.iter_mut() {
+            reader.read_exact(&mut page.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn displayed_page(&self) -> &Page {
+        &self.pages[self.cur_page_ptr2]
+    }
+
+    pub fn pages(&self) -> &[Page; 4] {
+        &self.pages
+    }
+
+    /// Page being drawn to, page on screen and the back buffer
+    pub fn page_ptrs(&self) -> (usize, usize, usize) {
+        (self.cur_page_ptr1, self.cur_page_ptr2, self.cur_page_ptr3)
+    }
+
+    /// Palette of the page on screen
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
     pub fn change_page_ptr1(&mut self, page_id: u8) {
         debug!("change_page_ptr1({})", page_id);
         self.cur_page_ptr1 = self.get_page_id(page_id);
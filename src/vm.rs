@@ -1,21 +1,32 @@
-use log::{debug, trace, warn};
+use log::{debug, error, info, trace, warn};
 use rand::random;
 use std::cmp;
-use std::io::{Cursor, Result};
-use std::sync::mpsc::Receiver;
-use std::sync::{Arc, RwLock};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, Cursor, Error, Result};
+use std::path::PathBuf;
 
+use crate::clock::{Clock, SystemClock};
+use crate::error;
 use crate::mixer;
-use crate::mixer::{Mixer, MixerAudio, MixerChunk};
+use crate::mixer::{MixerChunk, MixerHandle, OfflineOutput, Resampling, SoundKind, Volumes};
+use crate::movie::Movie;
 use crate::opcode::Opcode;
 use crate::parts;
-use crate::player::PlayerDirection;
+use crate::player::{PlayerDirection, PlayerInput};
+use crate::record::{RecordFormat, Recorder};
+use crate::replay::ReplayBuffer;
 use crate::resource::Resource;
-use crate::sfxplayer::SfxPlayer;
-use crate::sys::SDLSys;
+use crate::rewind::RewindBuffer;
+use crate::screenshot::{self, ScreenshotOptions};
+use crate::sfxplayer::{MusicEvent, SfxPlayer, SongEnd};
+use crate::state;
+use crate::sys::Backend;
 use crate::util;
 use crate::video::{Palette, Point, Video};
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
 const NUM_VARIABLES: usize = 256;
 const NUM_THREADS: usize = 64;
 const SET_INACTIVE_THREAD: usize = 0xfffe;
@@ -23,6 +34,9 @@ const INACTIVE_THREAD: usize = 0xffff;
 const COLOR_BLACK: u8 = 0xff;
 const DEFAULT_ZOOM: u32 = 0x40;
 const STACK_SIZE: usize = 0xff;
+const NO_REQUESTED_PC: u32 = 0xffff_ffff;
+const REWIND_INTERVAL: u64 = 4;
+const VOLUME_STEP: u8 = 10;
 
 const VM_VARIABLE_RANDOM_SEED: usize = 0x3c;
 const VM_VARIABLE_LAST_KEYCHAR: usize = 0xda;
@@ -36,6 +50,10 @@ const VM_VARIABLE_HERO_POS_MASK: usize = 0xfd;
 const VM_VARIABLE_HERO_ACTION_POS_MASK: usize = 0xfe;
 const VM_VARIABLE_PAUSE_SLICES: usize = 0xff;
 
+/// Music mark set when a song has played its last order, the marks in the
+/// game's music are all positive
+const MUSIC_END_MARK: i16 = -1;
+
 #[derive(Copy, Clone)]
 struct Thread {
     pc: usize,
@@ -55,6 +73,40 @@ impl Thread {
     }
 }
 
+/// Volume level changed by the volume keys
+#[derive(Copy, Clone)]
+enum VolumeControl {
+    Master,
+    Music,
+    Effects,
+}
+
+impl VolumeControl {
+    fn next(self) -> VolumeControl {
+        match self {
+            VolumeControl::Master => VolumeControl::Music,
+            VolumeControl::Music => VolumeControl::Effects,
+            VolumeControl::Effects => VolumeControl::Master,
+        }
+    }
+
+    fn level_mut(self, volumes: &mut Volumes) -> &mut u8 {
+        match self {
+            VolumeControl::Master => &mut volumes.master,
+            VolumeControl::Music => &mut volumes.music,
+            VolumeControl::Effects => &mut volumes.effects,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            VolumeControl::Master => "Master",
+            VolumeControl::Music => "Music",
+            VolumeControl::Effects => "Effects",
+        }
+    }
+}
+
 pub enum VideoBufferSeg {
     Cinematic,
     Video2,
@@ -63,36 +115,65 @@ pub enum VideoBufferSeg {
 pub struct VirtualMachine {
     variables: [i16; NUM_VARIABLES],
     threads: [Thread; NUM_THREADS],
-    mixer: Arc<RwLock<Mixer>>,
+    mixer: MixerHandle,
+    volumes: Volumes,
+    volume_control: VolumeControl,
     resource: Resource,
     video: Video,
     player: SfxPlayer,
+    music_resource: Option<(u16, u16)>,
+    music_ended: bool,
     requested_next_part: Option<u16>,
     script_ptr: usize,
     stack_ptr: usize,
     goto_next_thread: bool,
     video_buffer_seg: VideoBufferSeg,
     script_stack_calls: [usize; STACK_SIZE],
-    sys: SDLSys,
+    sys: Box<dyn Backend>,
+    clock: Box<dyn Clock>,
     last_timestamp: u64,
-    variable_receiver: Option<Receiver<i16>>,
     scale: u32,
+    frame_count: u64,
+    last_snapshot_frame: u64,
+    rewind_buffer: Option<RewindBuffer>,
+    movie: Option<Movie>,
+    paused: bool,
+    /// Clock time when the game was paused
+    paused_at: u64,
+    /// Clock time spent paused, which is not part of the rewind window
+    paused_time: u64,
+    text_input: bool,
+    screenshot_options: ScreenshotOptions,
+    recorder: Option<Recorder>,
+    /// Pulls the mixer by game time when the backend has no audio output
+    offline_output: Option<OfflineOutput>,
+    replay_buffer: Option<ReplayBuffer>,
 }
 
 impl VirtualMachine {
-    pub fn new(resource: Resource, video: Video, mut sys: SDLSys, scale: u32) -> VirtualMachine {
+    pub fn new(
+        resource: Resource,
+        video: Video,
+        mut sys: Box<dyn Backend>,
+        scale: u32,
+    ) -> VirtualMachine {
         let mut variables = [0; NUM_VARIABLES];
         variables[0x54] = 0x81;
         variables[VM_VARIABLE_RANDOM_SEED] = random::<i16>();
-        let mixer = Arc::new(RwLock::new(Mixer::new()));
-        sys.start_audio(mixer.clone());
+        let (mixer, audio) = MixerHandle::new();
+        let offline_output = sys.start_audio(audio).map(OfflineOutput::new);
+        let player = SfxPlayer::new();
         VirtualMachine {
             variables,
             threads: [Thread::new(); NUM_THREADS],
             mixer,
+            volumes: Volumes::new(),
+            volume_control: VolumeControl::Master,
             resource,
             video,
-            player: SfxPlayer::new(),
+            player,
+            music_resource: None,
+            music_ended: false,
             requested_next_part: None,
             script_ptr: 0,
             stack_ptr: 0,
@@ -100,27 +181,123 @@ impl VirtualMachine {
             video_buffer_seg: VideoBufferSeg::Cinematic,
             script_stack_calls: [0; STACK_SIZE],
             sys,
+            clock: Box::new(SystemClock::new(100)),
             last_timestamp: 0,
-            variable_receiver: None,
             scale,
+            frame_count: 0,
+            last_snapshot_frame: 0,
+            rewind_buffer: None,
+            movie: None,
+            paused: false,
+            paused_at: 0,
+            paused_time: 0,
+            text_input: false,
+            screenshot_options: ScreenshotOptions::new(),
+            recorder: None,
+            offline_output,
+            replay_buffer: None,
         }
     }
 
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+        self.last_timestamp = self.clock.get_timestamp();
+    }
+
+    /// Keeps snapshots of the last `duration` milliseconds of game time
+    pub fn enable_rewind(&mut self, duration: u64) {
+        self.rewind_buffer = Some(RewindBuffer::new(duration));
+    }
+
+    /// Keeps the last `duration` milliseconds of frames for GIF export
+    pub fn enable_replay(&mut self, duration: u64) {
+        self.replay_buffer = Some(ReplayBuffer::new(duration));
+    }
+
+    pub fn set_stereo_separation(&mut self, percent: u8) {
+        self.mixer.set_stereo_separation(percent);
+    }
+
+    /// Sets the mixing rate, for when no audio device decides it
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mixer.set_sample_rate(sample_rate);
+    }
+
+    pub fn set_resampling(&mut self, resampling: Resampling) {
+        self.mixer.set_resampling(resampling);
+    }
+
+    pub fn set_volumes(&mut self, volumes: Volumes) {
+        self.volumes = volumes;
+        self.mixer.set_volumes(volumes);
+    }
+
+    pub fn set_song_end(&mut self, song_end: SongEnd) {
+        self.player.set_song_end(song_end);
+    }
+
+    /// Whether the current music has played its last order, looped or not
+    pub fn has_music_ended(&self) -> bool {
+        self.music_ended
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Waits without running the game, for polling input while paused
+    pub fn idle(&mut self, ms: u64) {
+        self.clock.idle(ms);
+    }
+
+    pub fn set_screenshot_options(&mut self, options: ScreenshotOptions) {
+        self.screenshot_options = options;
+    }
+
+    /// Captures every presented frame and the mixer output to `output_dir`.
+    /// The audio device is stopped, so that the mixer is pulled by game time.
+    pub fn start_recording(&mut self, output_dir: PathBuf, format: RecordFormat) -> Result<()> {
+        if let Some(audio) = self.sys.stop_audio() {
+            self.offline_output = Some(OfflineOutput::new(audio));
+        }
+        let sample_rate = match self.offline_output.as_ref() {
+            Some(offline_output) => offline_output.sample_rate(),
+            None => return Err(Error::other("No mixer to record")),
+        };
+        self.recorder = Some(Recorder::create(
+            output_dir,
+            format,
+            sample_rate,
+            self.video.width,
+            self.video.height,
+        )?);
+        Ok(())
+    }
+
+    pub fn set_movie(&mut self, movie: Movie) {
+        self.movie = Some(movie);
+    }
+
+    pub fn random_seed(&self) -> i16 {
+        self.variables[VM_VARIABLE_RANDOM_SEED]
+    }
+
+    pub fn set_random_seed(&mut self, seed: i16) {
+        self.variables[VM_VARIABLE_RANDOM_SEED] = seed;
+    }
+
     pub fn set_variable(&mut self, var: usize, value: i16) {
         self.variables[var] = value;
     }
 
-    pub fn init_for_part(&mut self, part_id: u16) {
+    pub fn init_for_part(&mut self, part_id: u16) -> error::Result<()> {
         debug!("init_for_part: {}", part_id);
-        self.player.stop();
-        self.mixer
-            .write()
-            .expect("Expected non-poisoned RwLock")
-            .stop_all();
+        self.stop_music();
+        self.mixer.stop_all();
 
         self.variables[0xe4] = 0x14;
 
-        self.resource.setup_part(part_id);
+        self.resource.setup_part(part_id)?;
         if self.resource.copy_vid_ptr {
             let mut video_page_data = self.resource.video_page_data();
             debug!("init_for_part copy_vid_ptr: {}", video_page_data.len());
@@ -140,13 +317,14 @@ impl VirtualMachine {
         }
 
         self.threads[0].pc = 0;
+        Ok(())
     }
 
-    pub fn check_thread_requests(&mut self) {
+    pub fn check_thread_requests(&mut self) -> error::Result<()> {
         // Check if a part switch has been requested
         if let Some(part) = self.requested_next_part {
             trace!("New part requested: {}", part);
-            self.init_for_part(part);
+            self.init_for_part(part)?;
             self.requested_next_part = None;
         }
 
@@ -165,12 +343,30 @@ impl VirtualMachine {
                 trace!("Setting thread {} pc to 0x{:x}", thread_id, thread.pc);
             }
         }
+        Ok(())
     }
 
     pub fn update_player_input(&mut self) -> bool {
+        // The password screen reads typed characters instead of movement keys
+        let text_input = self.resource.current_part_id == parts::GAME_PART10;
+        if text_input != self.text_input {
+            self.sys.set_text_input(text_input);
+            self.text_input = text_input;
+        }
+
+        // Sends mixer commands held back while its queue was full
+        self.mixer.flush();
         let input = self.sys.process_events();
+        self.clock.set_turbo(input.turbo);
+        // Music marks are applied here, once per frame before any thread
+        // runs, so that they land at the same point when a movie replays
+        let music_mark = self.poll_music_mark();
+        let (input, music_mark) = self.apply_movie(input, music_mark);
+        if let Some(mark) = music_mark {
+            self.variables[VM_VARIABLE_MUS_MARK] = mark;
+        }
 
-        if self.resource.current_part_id == 0x3e89 {
+        if text_input {
             let c = input.last_char;
             if c == '\x08' || c == '\0' || (c >= 'A' && c <= 'Z') {
                 self.variables[VM_VARIABLE_LAST_KEYCHAR] = c as i16;
@@ -181,6 +377,53 @@ impl VirtualMachine {
             return false;
         }
 
+        if input.pause != self.paused {
+            info!("{}", if input.pause { "Paused" } else { "Resumed" });
+            self.paused = input.pause;
+            self.mixer.set_paused(self.paused);
+            let now = self.clock.get_timestamp();
+            if self.paused {
+                self.paused_at = now;
+            } else {
+                self.paused_time += now.saturating_sub(self.paused_at);
+            }
+        }
+
+        if input.screenshot {
+            match screenshot::save_screenshot(&self.video, &self.screenshot_options) {
+                Ok(path) => info!("Saved screenshot to {}", path.to_string_lossy()),
+                Err(e) => error!("Could not save screenshot: {}", e),
+            }
+        }
+
+        if input.replay {
+            self.save_replay();
+        }
+
+        self.update_volumes(&input);
+
+        if input.save {
+            match self.save_state_slot(input.state_slot) {
+                Ok(path) => info!("Saved state to {}", path.to_string_lossy()),
+                Err(e) => error!("Could not save state {}: {}", input.state_slot, e),
+            }
+        }
+
+        if input.load && self.movie.is_some() {
+            warn!("Loading states is disabled while a movie is active");
+        } else if input.load {
+            match self.load_state_slot(input.state_slot) {
+                Ok(()) => info!("Loaded state {}", input.state_slot),
+                Err(e) => error!("Could not load state {}: {}", input.state_slot, e),
+            }
+            if let Some(rewind_buffer) = self.rewind_buffer.as_mut() {
+                rewind_buffer.clear();
+            }
+        }
+
+        self.update_rewind(input.rewind && self.movie.is_none());
+        self.frame_count += 1;
+
         if input.code
             && self.resource.current_part_id != parts::GAME_PART_LAST
             && self.resource.current_part_id != parts::GAME_PART_FIRST
@@ -222,7 +465,267 @@ impl VirtualMachine {
         true
     }
 
-    pub fn host_frame(&mut self) {
+    fn apply_movie(
+        &mut self,
+        input: PlayerInput,
+        music_mark: Option<i16>,
+    ) -> (PlayerInput, Option<i16>) {
+        match self.movie.as_mut() {
+            Some(Movie::Recording(recorder)) => {
+                if let Err(e) = recorder.record(&input, music_mark) {
+                    error!("Could not record movie: {}", e);
+                    self.movie = None;
+                }
+                (input, music_mark)
+            }
+            Some(Movie::Playing(player)) => match player.next_frame() {
+                Ok(Some((mut recorded, recorded_mark))) => {
+                    recorded.quit = input.quit;
+                    recorded.turbo = input.turbo;
+                    recorded.screenshot = input.screenshot;
+                    recorded.replay = input.replay;
+                    recorded.volume_select = input.volume_select;
+                    recorded.volume_up = input.volume_up;
+                    recorded.volume_down = input.volume_down;
+                    recorded.mute = input.mute;
+                    if player.has_marks {
+                        (recorded, recorded_mark)
+                    } else {
+                        (recorded, music_mark)
+                    }
+                }
+                Ok(None) => {
+                    info!("Movie playback finished after {} frames", self.frame_count);
+                    self.movie = None;
+                    (input, music_mark)
+                }
+                Err(e) => {
+                    error!("Could not read movie: {}", e);
+                    self.movie = None;
+                    (input, music_mark)
+                }
+            },
+            None => (input, music_mark),
+        }
+    }
+
+    /// Takes the next music mark, leaving later ones queued for the
+    /// following frames. The end of a song counts as `MUSIC_END_MARK`.
+    /// Nothing is taken while paused, as no script would get to see it.
+    fn poll_music_mark(&mut self) -> Option<i16> {
+        let skipped_notes = self.player.take_skipped_notes();
+        if skipped_notes > 0 {
+            warn!("Skipped {} invalid music notes", skipped_notes);
+        }
+        if self.paused {
+            return None;
+        }
+        match self.player.poll_event() {
+            Some(MusicEvent::Mark(mark)) => {
+                debug!("Music mark {}", mark);
+                Some(mark)
+            }
+            Some(MusicEvent::End) => {
+                info!("Music ended");
+                self.music_ended = true;
+                if self.player.song_end() == SongEnd::Stop {
+                    self.music_resource = None;
+                }
+                Some(MUSIC_END_MARK)
+            }
+            None => None,
+        }
+    }
+
+    /// Applies the volume keys and shows the changed level
+    fn update_volumes(&mut self, input: &PlayerInput) {
+        if input.volume_select {
+            self.volume_control = self.volume_control.next();
+        }
+        let level = self.volume_control.level_mut(&mut self.volumes);
+        if input.volume_up {
+            *level = cmp::min(level.saturating_add(VOLUME_STEP), 100);
+        }
+        if input.volume_down {
+            *level = level.saturating_sub(VOLUME_STEP);
+        }
+        if input.mute {
+            self.volumes.muted = !self.volumes.muted;
+        }
+        if !(input.volume_select || input.volume_up || input.volume_down || input.mute) {
+            return;
+        }
+
+        self.mixer.set_volumes(self.volumes);
+        let level = *self.volume_control.level_mut(&mut self.volumes);
+        let message = if self.volumes.muted {
+            format!("{} volume {}% (muted)", self.volume_control.name(), level)
+        } else {
+            format!("{} volume {}%", self.volume_control.name(), level)
+        };
+        info!("{}", message);
+        self.sys.show_message(&message);
+    }
+
+    fn save_replay(&self) {
+        let replay_buffer = match self.replay_buffer.as_ref() {
+            Some(replay_buffer) => replay_buffer,
+            None => {
+                warn!("Instant replay is disabled");
+                return;
+            }
+        };
+        match screenshot::save_replay(replay_buffer, &self.video, &self.screenshot_options) {
+            Ok(path) => info!("Saved replay to {}", path.to_string_lossy()),
+            Err(e) => error!("Could not save replay: {}", e),
+        }
+    }
+
+    fn update_rewind(&mut self, rewinding: bool) {
+        let mut rewind_buffer = match self.rewind_buffer.take() {
+            Some(rewind_buffer) => rewind_buffer,
+            None => return,
+        };
+        if rewinding {
+            if let Some(state) = rewind_buffer.step_back() {
+                let state = state.to_vec();
+                if let Err(e) = self.load_state(&mut Cursor::new(&state)) {
+                    error!("Could not rewind: {}", e);
+                    rewind_buffer.clear();
+                }
+            }
+        } else if !self.paused && self.frame_count >= self.last_snapshot_frame + REWIND_INTERVAL {
+            self.last_snapshot_frame = self.frame_count;
+            let mut state = Vec::new();
+            match self.save_state(&mut state) {
+                Ok(()) => rewind_buffer.push(state, self.rewind_time()),
+                Err(e) => error!("Could not take rewind snapshot: {}", e),
+            }
+        }
+        self.rewind_buffer = Some(rewind_buffer);
+    }
+
+    /// Clock time without the time spent paused
+    fn rewind_time(&self) -> u64 {
+        self.clock.get_timestamp().saturating_sub(self.paused_time)
+    }
+
+    pub fn save_state_slot(&self, slot: i8) -> Result<PathBuf> {
+        let path = state::slot_path(slot)?;
+        let mut writer = BufWriter::new(File::create(&path)?);
+        state::write_header(&mut writer)?;
+        self.save_state(&mut writer)?;
+        writer.flush()?;
+        Ok(path)
+    }
+
+    pub fn load_state_slot(&mut self, slot: i8) -> Result<()> {
+        let path = state::slot_path(slot)?;
+        let mut reader = BufReader::new(File::open(&path)?);
+        state::read_header(&mut reader)?;
+
+        // Keep the current state around so a truncated or mismatching
+        // file does not leave the machine half restored
+        let mut backup = Vec::new();
+        self.save_state(&mut backup)?;
+        if let Err(e) = self.load_state(&mut reader) {
+            self.load_state(&mut Cursor::new(&backup))?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Writes everything needed to resume the game at the current frame
+    pub fn save_state<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for variable in self.variables.iter() {
+            writer.write_i16::<BigEndian>(*variable)?;
+        }
+        for thread in self.threads.iter() {
+            writer.write_u32::<BigEndian>(thread.pc as u32)?;
+            let requested_pc = thread
+                .requested_pc_offset
+                .map_or(NO_REQUESTED_PC, |pc| pc as u32);
+            writer.write_u32::<BigEndian>(requested_pc)?;
+            writer.write_u8(thread.is_channel_active_current as u8)?;
+            writer.write_u8(thread.is_channel_active_requested as u8)?;
+        }
+        writer.write_u16::<BigEndian>(self.stack_ptr as u16)?;
+        for call in self.script_stack_calls.iter() {
+            writer.write_u32::<BigEndian>(*call as u32)?;
+        }
+        writer.write_u16::<BigEndian>(self.requested_next_part.unwrap_or(0))?;
+
+        let (resource_id, delay, order, cur_pos) =
+            match (self.music_resource, self.mixer.music_position()) {
+                (Some((resource_id, delay)), Some((order, cur_pos))) => {
+                    (resource_id, delay, order, cur_pos)
+                }
+                _ => (0, 0, 0, 0),
+            };
+        writer.write_u16::<BigEndian>(resource_id)?;
+        writer.write_u16::<BigEndian>(delay)?;
+        writer.write_u8(order)?;
+        writer.write_u16::<BigEndian>(cur_pos as u16)?;
+
+        self.resource.save_state(writer)?;
+        self.video.save_state(writer)
+    }
+
+    pub fn load_state<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        for variable in self.variables.iter_mut() {
+            *variable = reader.read_i16::<BigEndian>()?;
+        }
+        for thread in self.threads.iter_mut() {
+            thread.pc = reader.read_u32::<BigEndian>()? as usize;
+            let requested_pc = reader.read_u32::<BigEndian>()?;
+            thread.requested_pc_offset = if requested_pc == NO_REQUESTED_PC {
+                None
+            } else {
+                Some(requested_pc as usize)
+            };
+            thread.is_channel_active_current = reader.read_u8()? != 0;
+            thread.is_channel_active_requested = reader.read_u8()? != 0;
+        }
+        self.stack_ptr = cmp::min(reader.read_u16::<BigEndian>()? as usize, STACK_SIZE);
+        for call in self.script_stack_calls.iter_mut() {
+            *call = reader.read_u32::<BigEndian>()? as usize;
+        }
+        self.requested_next_part = match reader.read_u16::<BigEndian>()? {
+            0 => None,
+            part => Some(part),
+        };
+
+        let resource_id = reader.read_u16::<BigEndian>()?;
+        let delay = reader.read_u16::<BigEndian>()?;
+        let order = reader.read_u8()?;
+        let cur_pos = reader.read_u16::<BigEndian>()? as usize;
+
+        self.resource.load_state(reader)?;
+        self.video.load_state(reader)?;
+
+        // Rewinding loads a state every frame, music that already plays
+        // the same order carries on instead of restarting each time
+        let playing = match (self.music_resource, self.mixer.music_position()) {
+            (Some((playing_id, _)), Some((playing_order, _))) => Some((playing_id, playing_order)),
+            _ => None,
+        };
+        if resource_id != 0 && playing == Some((resource_id, order)) {
+            if self.music_resource != Some((resource_id, delay)) {
+                self.player.set_events_delay(&mut self.mixer, delay);
+                self.music_resource = Some((resource_id, delay));
+            }
+        } else {
+            self.stop_music();
+            self.mixer.stop_all();
+            if resource_id != 0 {
+                self.start_music(resource_id, delay, order, cur_pos)?;
+            }
+        }
+        self.video.update_display(&mut *self.sys, 0xfe);
+        Ok(())
+    }
+
+    pub fn host_frame(&mut self) -> error::Result<()> {
         for thread_id in 0..self.threads.len() {
             if self.threads[thread_id].is_channel_active_current {
                 trace!("Skip thread {}", thread_id);
@@ -238,19 +741,21 @@ impl VirtualMachine {
 
                 trace!("host_frame() thread_id=0x{:02x} n=0x{:02x}", thread_id, n);
 
-                self.execute_thread();
+                self.execute_thread()?;
 
                 // Save pc since it will be modified on the next iteration
                 self.threads[thread_id].pc = self.script_ptr - self.resource.seg_bytecode;
 
                 trace!(
                     "host_frame() thread_id=0x{:02x} pos=0x{:x}",
-                    thread_id, self.threads[thread_id].pc
+                    thread_id,
+                    self.threads[thread_id].pc
                 );
 
                 // if input.quit { break }....
             }
         }
+        Ok(())
     }
 
     fn fetch_byte(&mut self) -> u8 {
@@ -265,14 +770,8 @@ impl VirtualMachine {
         result
     }
 
-    fn execute_thread(&mut self) {
+    fn execute_thread(&mut self) -> error::Result<()> {
         while !self.goto_next_thread {
-            if let Some(rx) = &self.variable_receiver {
-                if let Ok(value) = rx.try_recv() {
-                    warn!("Got variable value from sfxplayer: {}", value);
-                    self.variables[VM_VARIABLE_MUS_MARK] = value;
-                }
-            }
             trace!("pc: 0x{:x} Decoding opcode", self.script_ptr);
             let opcode = Opcode::decode(self.fetch_byte());
 
@@ -302,12 +801,13 @@ impl VirtualMachine {
                 Opcode::Shl => self.op_shl(),
                 Opcode::Shr => self.op_shr(),
                 Opcode::PlaySound => self.op_play_sound(),
-                Opcode::UpdateMemList => self.op_update_memlist(),
-                Opcode::PlayMusic => self.op_play_music(),
+                Opcode::UpdateMemList => self.op_update_memlist()?,
+                Opcode::PlayMusic => self.op_play_music()?,
                 Opcode::DrawPolySprite(val) => self.op_draw_poly_sprite(val),
                 Opcode::DrawPolyBackground(val) => self.op_draw_poly_background(val),
             }
         }
+        Ok(())
     }
 
     // Opcode implementation
@@ -379,7 +879,8 @@ impl VirtualMachine {
         let pc_offset_requested = self.fetch_word() as usize;
         trace!(
             "set_set_vect(0x{:02x}, 0x{:x})",
-            thread_id, pc_offset_requested
+            thread_id,
+            pc_offset_requested
         );
         self.threads[thread_id].requested_pc_offset = Some(pc_offset_requested);
     }
@@ -410,7 +911,10 @@ impl VirtualMachine {
         };
         trace!(
             "op_cond_jmp({}, 0x{:02x}, 0x{:02x}) var=0x{:02x}",
-            opcode, b, a, var
+            opcode,
+            b,
+            a,
+            var
         );
 
         let expr = match opcode & 7 {
@@ -436,7 +940,10 @@ impl VirtualMachine {
                 _ => " unsupported ",
             };
 
-            warn!("Checking music variable {} {} {} = {:?}", b, operator, a, expr);
+            warn!(
+                "Checking music variable {} {} {} = {:?}",
+                b, operator, a, expr
+            );
         }
 
         if expr {
@@ -524,18 +1031,34 @@ impl VirtualMachine {
         trace!("blit_frame_buffer({})", page_id);
         //inp_handle_special_keys();
 
-        let delay = self.sys.get_timestamp() - self.last_timestamp;
+        let delay = self
+            .clock
+            .get_timestamp()
+            .saturating_sub(self.last_timestamp);
 
         let pause_time = self.variables[VM_VARIABLE_PAUSE_SLICES] as u64 * 20;
         if pause_time > delay {
             let time_to_sleep = pause_time - delay;
-            self.sys.sleep(time_to_sleep);
+            self.clock.sleep(time_to_sleep);
             trace!("Delay: {}, time_to_sleep: {}", delay, time_to_sleep);
         }
-        self.last_timestamp = self.sys.get_timestamp();
+        self.last_timestamp = self.clock.get_timestamp();
 
         self.variables[0xf7] = 0;
-        self.video.update_display(&mut self.sys, page_id);
+        self.video.update_display(&mut *self.sys, page_id);
+
+        if let Some(replay_buffer) = self.replay_buffer.as_mut() {
+            replay_buffer.push(&self.video, pause_time);
+        }
+        if let Some(offline_output) = self.offline_output.as_mut() {
+            let samples = offline_output.render_until(self.last_timestamp);
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(e) = recorder.frame(&self.video, self.last_timestamp, samples) {
+                    error!("Could not record frame, stopping recording: {}", e);
+                    self.recorder = None;
+                }
+            }
+        }
     }
 
     fn op_kill_thread(&mut self) {
@@ -549,7 +1072,8 @@ impl VirtualMachine {
         let x = self.fetch_byte() as u16;
         let y = self.fetch_byte() as u16;
         let color = self.fetch_byte();
-        self.video.draw_string_id(color, x, y, string_id, self.scale);
+        self.video
+            .draw_string_id(color, x, y, string_id, self.scale);
     }
 
     fn op_sub(&mut self) {
@@ -594,27 +1118,27 @@ impl VirtualMachine {
         let channel = self.fetch_byte();
         trace!(
             "play_sound(0x{:x}, {}, {}, {})",
-            resource_id, freq, vol, channel
+            resource_id,
+            freq,
+            vol,
+            channel
         );
         self.play_sound_resource(resource_id, freq, vol, channel);
     }
 
-    fn op_update_memlist(&mut self) {
+    fn op_update_memlist(&mut self) -> error::Result<()> {
         let resource_id = self.fetch_word();
         trace!("update_memlist({})", resource_id);
 
         if resource_id == 0 {
-            self.player.stop();
-            self.mixer
-                .write()
-                .expect("Expected non-poisoned RwLock")
-                .stop_all();
+            self.stop_music();
+            self.mixer.stop_all();
             self.resource.invalidate_resource();
         } else if resource_id >= parts::GAME_PART_FIRST {
             debug!("Requesting new part {}", resource_id);
             self.requested_next_part = Some(resource_id);
         } else {
-            self.resource.load_memory_entry(resource_id);
+            self.resource.load_memory_entry(resource_id)?;
             if self.resource.copy_vid_ptr {
                 let mut video_page_data = self.resource.video_page_data();
                 debug!("update_memlist copy_vid_ptr: {}", video_page_data.len());
@@ -625,13 +1149,14 @@ impl VirtualMachine {
                 self.resource.copy_vid_ptr = false;
             }
         }
+        Ok(())
     }
 
-    fn op_play_music(&mut self) {
+    fn op_play_music(&mut self) -> error::Result<()> {
         let resource_id = self.fetch_word();
         let delay = self.fetch_word();
         let pos = self.fetch_byte();
-        self.play_music_resource(resource_id, delay, pos).unwrap();
+        self.play_music_resource(resource_id, delay, pos)
     }
 
     fn op_draw_poly_sprite(&mut self, val: u8) {
@@ -682,7 +1207,10 @@ impl VirtualMachine {
         }
         trace!(
             "draw_poly_sprite() offset=0x{:x}, x={}, y={}, zoom={}",
-            offset, x, y, zoom
+            offset,
+            x,
+            y,
+            zoom
         );
         let segment = match self.video_buffer_seg {
             VideoBufferSeg::Cinematic => self.resource.seg_cinematic,
@@ -719,7 +1247,10 @@ impl VirtualMachine {
         }
         trace!(
             "DrawPolyBackground: val: 0x{:02x} off={} x={} y={}",
-            val, offset, x, y
+            val,
+            offset,
+            x,
+            y
         );
 
         let mut buffer = Cursor::new(&self.resource.memory[self.resource.seg_cinematic..]);
@@ -735,14 +1266,13 @@ impl VirtualMachine {
     }
 
     fn stop_channel(&mut self, channel: u8) {
-        let mut write_guard = self.mixer.write().expect("Expected non-poisoned RwLock");
-        write_guard.stop_channel(channel);
+        self.mixer.stop_channel(channel);
     }
 
     fn play_channel(&mut self, channel: u8, mixer_chunk: MixerChunk, frequence: u16, vol: u8) {
-        let mut write_guard = self.mixer.write().expect("Expected non-poisoned RwLock");
         let vol = cmp::min(vol, 0x3f);
-        write_guard.play_channel(channel & 3, mixer_chunk, frequence, vol);
+        self.mixer
+            .play_channel(channel & 3, mixer_chunk, frequence, vol, SoundKind::Effect);
     }
 
     fn play_sound_resource(&mut self, resource_id: u16, freq: u8, vol: u8, channel: u8) {
@@ -759,28 +1289,231 @@ impl VirtualMachine {
         }
     }
 
-    fn play_music_resource(&mut self, resource_id: u16, delay: u16, pos: u8) -> Result<()> {
+    fn play_music_resource(&mut self, resource_id: u16, delay: u16, pos: u8) -> error::Result<()> {
         debug!(
             "play_music_resource(0x{:x}, {}, {})",
             resource_id, delay, pos
         );
         if resource_id != 0 {
-            let mut delay = delay;
-            if let Some(sfx_module) = self
-                .resource
-                .load_sfx_module(resource_id, &mut delay, pos)?
-            {
-                self.player.set_sfx_module(sfx_module);
-                self.player.set_events_delay(delay);
-
-                self.variable_receiver
-                    .replace(self.player.start(MixerAudio(self.mixer.clone())));
-            }
+            self.start_music(resource_id, delay, pos, 0)?;
         } else if delay != 0 {
-            self.player.set_events_delay(delay);
+            self.player.set_events_delay(&mut self.mixer, delay);
+            if let Some((resource_id, _)) = self.music_resource {
+                self.music_resource = Some((resource_id, delay));
+            }
         } else {
-            self.player.stop();
+            self.stop_music();
         }
         Ok(())
     }
+
+    fn start_music(
+        &mut self,
+        resource_id: u16,
+        delay: u16,
+        order: u8,
+        cur_pos: usize,
+    ) -> error::Result<()> {
+        let mut delay = delay;
+        if let Some(mut sfx_module) =
+            self.resource
+                .load_sfx_module(resource_id, &mut delay, order)?
+        {
+            sfx_module.set_cur_pos(cur_pos);
+            self.player.set_sfx_module(sfx_module);
+            self.player.set_events_delay(&mut self.mixer, delay);
+
+            self.player.start(&mut self.mixer);
+            self.music_resource = Some((resource_id, delay));
+            self.music_ended = false;
+        }
+        Ok(())
+    }
+
+    fn stop_music(&mut self) {
+        self.player.stop(&mut self.mixer);
+        self.music_resource = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::resource::AssetPlatform;
+    use crate::testutil::StubSys;
+
+    fn new_vm(inputs: Vec<PlayerInput>) -> VirtualMachine {
+        let resource = Resource::new(Vec::new(), PathBuf::new(), AssetPlatform::PC);
+        let sys = StubSys::new(inputs);
+        VirtualMachine::new(resource, Video::new(320, 200), Box::new(sys), 1)
+    }
+
+    fn saved_state(vm: &VirtualMachine) -> Vec<u8> {
+        let mut state = Vec::new();
+        vm.save_state(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut vm = new_vm(Vec::new());
+        vm.variables[0x10] = -1234;
+        vm.threads[3].pc = 0x42;
+        vm.threads[5].requested_pc_offset = Some(0x1000);
+        vm.threads[5].is_channel_active_requested = true;
+        vm.stack_ptr = 2;
+        vm.script_stack_calls[1] = 0x99;
+        vm.requested_next_part = Some(parts::GAME_PART3);
+        vm.resource.memory[100] = 5;
+        vm.resource.seg_bytecode = 0x800;
+        vm.video.fill_video_page(1, 7);
+        let state = saved_state(&vm);
+
+        let mut restored = new_vm(Vec::new());
+        restored.load_state(&mut Cursor::new(&state)).unwrap();
+        assert_eq!(restored.variables[0x10], -1234);
+        assert_eq!(restored.threads[3].pc, 0x42);
+        assert_eq!(restored.threads[5].requested_pc_offset, Some(0x1000));
+        assert_eq!(restored.requested_next_part, Some(parts::GAME_PART3));
+        assert_eq!(restored.resource.seg_bytecode, 0x800);
+        assert!(saved_state(&restored) == state);
+    }
+
+    /// Runs one frame and returns the music mark it applied, if any
+    fn frame_mark(vm: &mut VirtualMachine) -> Option<i16> {
+        vm.variables[VM_VARIABLE_MUS_MARK] = 0;
+        assert!(vm.update_player_input());
+        match vm.variables[VM_VARIABLE_MUS_MARK] {
+            0 => None,
+            mark => Some(mark),
+        }
+    }
+
+    #[test]
+    fn music_marks_wait_while_paused() {
+        let paused = PlayerInput {
+            pause: true,
+            ..PlayerInput::new()
+        };
+        let mut vm = new_vm(vec![
+            PlayerInput::new(),
+            paused,
+            paused,
+            PlayerInput::new(),
+            PlayerInput::new(),
+            PlayerInput::new(),
+        ]);
+        vm.player.send_event(MusicEvent::Mark(1));
+        vm.player.send_event(MusicEvent::Mark(2));
+        // One mark per frame, in order
+        assert_eq!(frame_mark(&mut vm), Some(1));
+        // Taken before the pause key is handled
+        assert_eq!(frame_mark(&mut vm), Some(2));
+        assert!(vm.is_paused());
+
+        vm.player.send_event(MusicEvent::Mark(3));
+        vm.player.send_event(MusicEvent::Mark(4));
+        assert_eq!(frame_mark(&mut vm), None);
+        // Resumes at the end of this frame
+        assert_eq!(frame_mark(&mut vm), None);
+        assert!(!vm.is_paused());
+        assert_eq!(frame_mark(&mut vm), Some(3));
+        assert_eq!(frame_mark(&mut vm), Some(4));
+    }
+
+    #[test]
+    fn song_end_sets_the_music_mark() {
+        let mut vm = new_vm(vec![PlayerInput::new(); 3]);
+        vm.music_resource = Some((0x10, 120));
+        vm.player.send_event(MusicEvent::End);
+        vm.player.send_event(MusicEvent::Mark(1));
+        assert_eq!(frame_mark(&mut vm), Some(MUSIC_END_MARK));
+        assert!(vm.has_music_ended());
+        assert_eq!(vm.music_resource, None);
+        assert_eq!(frame_mark(&mut vm), Some(1));
+        assert_eq!(frame_mark(&mut vm), None);
+    }
+
+    /// Clock that the test moves by hand
+    struct ManualClock(Rc<Cell<u64>>);
+
+    impl Clock for ManualClock {
+        fn get_timestamp(&self) -> u64 {
+            self.0.get()
+        }
+
+        fn sleep(&mut self, ms: u64) {
+            self.0.set(self.0.get() + ms);
+        }
+
+        fn idle(&mut self, _ms: u64) {}
+
+        fn set_turbo(&mut self, _turbo: bool) {}
+    }
+
+    #[test]
+    fn rewind_ignores_paused_time() {
+        let paused = PlayerInput {
+            pause: true,
+            ..PlayerInput::new()
+        };
+        let mut inputs = vec![PlayerInput::new(); 8];
+        inputs.extend(vec![paused; 20]);
+        inputs.extend(vec![PlayerInput::new(); 8]);
+        let mut vm = new_vm(inputs);
+        let now = Rc::new(Cell::new(0));
+        vm.set_clock(Box::new(ManualClock(now.clone())));
+        vm.enable_rewind(1000);
+
+        let frame = |vm: &mut VirtualMachine| {
+            assert!(vm.update_player_input());
+            now.set(now.get() + 20);
+        };
+        for _ in 0..8 {
+            frame(&mut vm);
+        }
+        let snapshot_frame = vm.last_snapshot_frame;
+        for _ in 0..20 {
+            frame(&mut vm);
+        }
+        // Paused for a long time
+        now.set(now.get() + 60_000);
+        assert_eq!(vm.last_snapshot_frame, snapshot_frame);
+
+        for _ in 0..8 {
+            frame(&mut vm);
+        }
+        assert!(vm.last_snapshot_frame > snapshot_frame);
+        assert!(vm.rewind_time() < 1000);
+    }
+
+    #[test]
+    fn replay_frames_last_for_the_pause_slices() {
+        let mut vm = new_vm(Vec::new());
+        let now = Rc::new(Cell::new(0));
+        vm.set_clock(Box::new(ManualClock(now.clone())));
+        vm.enable_replay(1000);
+        vm.resource.memory[0] = 0xfe;
+        vm.resource.memory[1] = 0xfe;
+        vm.script_ptr = 0;
+        for slices in &[3, 5] {
+            vm.variables[VM_VARIABLE_PAUSE_SLICES] = *slices;
+            vm.op_blit_frame_buffer();
+        }
+        assert_eq!(now.get(), 160);
+        let replay_buffer = vm.replay_buffer.as_ref().unwrap();
+        assert_eq!(replay_buffer.durations(), vec![100, 100]);
+    }
+
+    #[test]
+    fn truncated_state_is_rejected() {
+        let state = saved_state(&new_vm(Vec::new()));
+        let mut vm = new_vm(Vec::new());
+        assert!(vm
+            .load_state(&mut Cursor::new(&state[..state.len() / 2]))
+            .is_err());
+    }
 }